@@ -0,0 +1,73 @@
+//! Dump a PockyStation savestate as a tree of structs and fields, or
+//! compare two savestates field by field.
+//!
+//! Usage:
+//!
+//! ```text
+//! savestate-inspect <state>
+//! savestate-inspect <state-a> <state-b>
+//! ```
+
+extern crate pockystation_retro;
+
+use std::env;
+use std::fs::File;
+use std::io;
+use std::process;
+
+use pockystation_retro::inspect;
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    match args.len() {
+        1 => {
+            let state = load(&args[0]);
+
+            let stdout = io::stdout();
+
+            if let Err(e) = state.print(&mut stdout.lock(), &args[0], 0) {
+                die(&format!("Write failed: {}", e));
+            }
+        }
+        2 => {
+            let a = load(&args[0]);
+            let b = load(&args[1]);
+
+            let differences = inspect::diff(&a, &b);
+
+            for d in &differences {
+                println!("{}", d);
+            }
+
+            if !differences.is_empty() {
+                process::exit(1);
+            }
+        }
+        _ => {
+            eprintln!("Usage: savestate-inspect <state> [<other-state>]");
+            eprintln!();
+            eprintln!("With one savestate print its contents, with two \
+                       print the fields that differ.");
+            process::exit(2);
+        }
+    }
+}
+
+fn load(path: &str) -> inspect::Node {
+    let mut file =
+        match File::open(path) {
+            Ok(f) => f,
+            Err(e) => die(&format!("Can't open {}: {}", path, e)),
+        };
+
+    match inspect::load(&mut file) {
+        Ok(n) => n,
+        Err(e) => die(&format!("Can't decode {}: {}", path, e)),
+    }
+}
+
+fn die(msg: &str) -> ! {
+    eprintln!("{}", msg);
+    process::exit(2);
+}
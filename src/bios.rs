@@ -0,0 +1,213 @@
+//! BIOS lookup. Every file of the right size in the system directory
+//! is a candidate, candidates are hashed and compared against a table
+//! of known-good dumps.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use crc32fast;
+use sha1_smol::Sha1;
+
+use pockystation::memory::bios::{Bios, BIOS_SIZE};
+
+/// A known-good BIOS dump
+pub struct KnownBios {
+    /// Human readable description of the dump
+    pub description: &'static str,
+    /// CRC32 of the dump
+    pub crc32: u32,
+    /// SHA-1 of the dump as a lowercase hexadecimal string
+    pub sha1: &'static str,
+}
+
+/// Table of the known-good BIOS dumps. The hashes of the SCPH-4000
+/// kernel are those of the `kernel.bin` ROM listed in MAME's
+/// `pockstat` driver (src/mame/sony/pockstat.cpp).
+pub const KNOWN_BIOSES: [KnownBios; 1] = [
+    KnownBios {
+        description: "SCPH-4000 kernel",
+        crc32: 0x5fb47dd8,
+        sha1: "6ae880493ddde880827d1e9f08e9cb2c38f9f2ec",
+    },
+];
+
+/// BIOS file found in the system directory
+pub struct Candidate {
+    /// Path of the file
+    pub path: PathBuf,
+    /// Contents of the file
+    pub data: Vec<u8>,
+    /// CRC32 of the contents
+    pub crc32: u32,
+    /// SHA-1 of the contents as a lowercase hexadecimal string
+    pub sha1: String,
+    /// What we think of this file
+    pub verdict: Verdict,
+}
+
+impl Candidate {
+    /// Read and check the file at `path`. Returns `None` if the file
+    /// can't be read or doesn't have the size of a BIOS.
+    fn load(path: &Path) -> Option<Candidate> {
+        let mut data = Vec::new();
+
+        let res =
+            File::open(path)
+            .and_then(|f| f.take(BIOS_SIZE as u64 + 1).read_to_end(&mut data));
+
+        if let Err(e) = res {
+            warn!("Can't read {:?}: {}", path, e);
+            return None;
+        }
+
+        if data.len() != BIOS_SIZE as usize {
+            debug!("Ignoring {:?}: bad size", path);
+            return None;
+        }
+
+        let mut crc32 = crc32fast::Hasher::new();
+        crc32.update(&data);
+        let crc32 = crc32.finalize();
+
+        let sha1 = Sha1::from(&data).digest().to_string();
+
+        let known =
+            KNOWN_BIOSES.iter()
+            .find(|k| k.crc32 == crc32 && k.sha1 == sha1);
+
+        let verdict =
+            if Bios::new(&data).is_none() {
+                Verdict::Rejected
+            } else {
+                match known {
+                    Some(k) => Verdict::Known(k.description),
+                    None => Verdict::Unknown,
+                }
+            };
+
+        Some(Candidate {
+            path: path.to_path_buf(),
+            data: data,
+            crc32: crc32,
+            sha1: sha1,
+            verdict: verdict,
+        })
+    }
+
+    /// Return the name of the file without the directory
+    pub fn file_name(&self) -> Option<&str> {
+        self.path.file_name().and_then(|n| n.to_str())
+    }
+}
+
+/// Result of the BIOS checks
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Verdict {
+    /// Matches this dump in `KNOWN_BIOSES`
+    Known(&'static str),
+    /// Accepted by the emulator but not in `KNOWN_BIOSES`, could be a
+    /// bad dump or a revision we don't know about
+    Unknown,
+    /// Rejected by the emulator
+    Rejected,
+}
+
+/// Return all the BIOS candidates found in `dir`, sorted by file
+/// name so that the selection doesn't depend on the order the
+/// filesystem returns the entries in.
+pub fn candidates(dir: &Path) -> Vec<Candidate> {
+    let entries =
+        match ::std::fs::read_dir(dir) {
+            Ok(d) => d,
+            Err(e) => {
+                error!("Can't read directory {:?}: {}", dir, e);
+                return Vec::new();
+            }
+        };
+
+    let mut paths = Vec::new();
+
+    for entry in entries {
+        match entry {
+            Ok(entry) => {
+                let path = entry.path();
+
+                match entry.metadata() {
+                    Ok(md) => {
+                        if !md.is_file() {
+                            debug!("Ignoring {:?}: not a file", path);
+                        } else if md.len() != BIOS_SIZE as u64 {
+                            debug!("Ignoring {:?}: bad size", path);
+                        } else {
+                            paths.push(path);
+                        }
+                    }
+                    Err(e) =>
+                        warn!("Ignoring {:?}: can't get file metadata: {}",
+                              path, e)
+                }
+            }
+            Err(e) => warn!("Error while reading directory: {}", e),
+        }
+    }
+
+    paths.sort();
+
+    let candidates: Vec<Candidate> =
+        paths.iter().filter_map(|p| Candidate::load(p)).collect();
+
+    for c in &candidates {
+        info!("BIOS candidate {:?}: CRC32 {:08x} SHA-1 {}: {:?}",
+              c.path, c.crc32, c.sha1, c.verdict);
+    }
+
+    candidates
+}
+
+/// Pick a BIOS among `candidates`. If `file_name` is given only that
+/// file is considered, otherwise we use the first known-good dump or,
+/// failing that, the first file accepted by the emulator.
+pub fn select<'a>(candidates: &'a [Candidate],
+                  file_name: Option<&str>) -> Option<&'a Candidate> {
+    if let Some(name) = file_name {
+        let candidate =
+            match candidates.iter().find(|c| c.file_name() == Some(name)) {
+                Some(c) => c,
+                None => {
+                    error!("BIOS {:?} not found in the system directory", name);
+                    return None;
+                }
+            };
+
+        return match candidate.verdict {
+            Verdict::Known(_) => Some(candidate),
+            Verdict::Unknown => {
+                warn!("BIOS {:?} is not a known-good dump, using it anyway",
+                      candidate.path);
+                Some(candidate)
+            }
+            Verdict::Rejected => {
+                error!("BIOS {:?} is not a valid PocketStation BIOS",
+                       candidate.path);
+                None
+            }
+        };
+    }
+
+    let known =
+        candidates.iter()
+        .find(|c| match c.verdict { Verdict::Known(_) => true, _ => false });
+
+    if known.is_some() {
+        return known;
+    }
+
+    let unknown = candidates.iter().find(|c| c.verdict == Verdict::Unknown);
+
+    if let Some(c) = unknown {
+        warn!("No known-good BIOS found, falling back on {:?}", c.path);
+    }
+
+    unknown
+}
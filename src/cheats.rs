@@ -0,0 +1,265 @@
+//! GameShark-style cheat code engine
+//!
+//! Each code is made of two 32bit hexadecimal words `AAAAAAAA
+//! VVVVVVVV`. The top byte of the first word is the code type, the
+//! low 24 bits are the target address. A single cheat can contain
+//! several codes separated by whitespace or `+` (the separator used
+//! by RetroArch for multi-line cheats).
+//!
+//! Supported code types:
+//!
+//! * `00aaaaaa 000000vv`: poke byte `vv` directly in RAM at offset
+//!   `aaaaaa`, bypassing the memory bus
+//! * `30aaaaaa 000000vv`: write byte `vv` at `aaaaaa`
+//! * `80aaaaaa 0000vvvv`: write halfword `vvvv` at `aaaaaa`
+//! * `90aaaaaa vvvvvvvv`: write word `vvvvvvvv` at `aaaaaa`
+//! * `d0aaaaaa 0000vvvv`: apply the next code only if the halfword at
+//!   `aaaaaa` is equal to `vvvv`
+//! * `d1aaaaaa 0000vvvv`: apply the next code only if the halfword at
+//!   `aaaaaa` is different from `vvvv`
+//! * `e0aaaaaa 000000vv`: apply the next code only if the byte at
+//!   `aaaaaa` is equal to `vv`
+//! * `e1aaaaaa 000000vv`: apply the next code only if the byte at
+//!   `aaaaaa` is different from `vv`
+
+use std::collections::BTreeMap;
+
+use pockystation::cpu::Cpu;
+use pockystation::memory::{Byte, HalfWord, Word};
+
+/// Set of cheats registered by the frontend
+pub struct Cheats {
+    /// Enabled cheats indexed by the frontend's cheat index. The
+    /// index is controlled by the frontend so we don't use it to size
+    /// a `Vec`.
+    cheats: BTreeMap<usize, Vec<Code>>,
+}
+
+impl Cheats {
+    pub fn new() -> Cheats {
+        Cheats {
+            cheats: BTreeMap::new(),
+        }
+    }
+
+    /// Remove all cheats
+    pub fn reset(&mut self) {
+        self.cheats.clear();
+    }
+
+    /// Parse `code` and store it at `index`, replacing any previous
+    /// cheat with the same index. If `enabled` is false the cheat is
+    /// removed. `ram_len` is the size of the emulated RAM, used to
+    /// validate direct RAM pokes.
+    pub fn set(&mut self,
+               index: usize,
+               enabled: bool,
+               code: &str,
+               ram_len: usize) -> Result<(), Error> {
+        if enabled {
+            let codes = try!(parse(code, ram_len));
+
+            self.cheats.insert(index, codes);
+        } else {
+            self.cheats.remove(&index);
+        }
+
+        Ok(())
+    }
+
+    /// Apply all the active cheats. Should be called once per frame.
+    pub fn apply(&self, cpu: &mut Cpu) {
+        for codes in self.cheats.values() {
+            // If false the next code is skipped
+            let mut condition = true;
+
+            for &code in codes {
+                if !condition {
+                    condition = true;
+                    continue;
+                }
+
+                match code {
+                    Code::RamPoke(off, v) => {
+                        let ram = cpu.interconnect_mut().ram_mut().data_mut();
+
+                        // The offset is checked against the RAM size
+                        // when the code is parsed
+                        if let Some(b) = ram.get_mut(off as usize) {
+                            *b = v;
+                        }
+                    }
+                    Code::Write8(addr, v) =>
+                        cpu.interconnect_mut().store::<Byte>(addr, v as u32),
+                    Code::Write16(addr, v) =>
+                        cpu.interconnect_mut().store::<HalfWord>(addr, v as u32),
+                    Code::Write32(addr, v) =>
+                        cpu.interconnect_mut().store::<Word>(addr, v),
+                    Code::IfEqual16(addr, v) =>
+                        condition = cpu.examine::<HalfWord>(addr) == v as u32,
+                    Code::IfNotEqual16(addr, v) =>
+                        condition = cpu.examine::<HalfWord>(addr) != v as u32,
+                    Code::IfEqual8(addr, v) =>
+                        condition = cpu.examine::<Byte>(addr) == v as u32,
+                    Code::IfNotEqual8(addr, v) =>
+                        condition = cpu.examine::<Byte>(addr) != v as u32,
+                }
+            }
+        }
+    }
+}
+
+/// A single `AAAAAAAA VVVVVVVV` code
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Code {
+    /// Write a byte directly in RAM: `(offset, value)`
+    RamPoke(u32, u8),
+    /// Write a byte on the memory bus: `(address, value)`
+    Write8(u32, u8),
+    /// Write a halfword on the memory bus: `(address, value)`
+    Write16(u32, u16),
+    /// Write a word on the memory bus: `(address, value)`
+    Write32(u32, u32),
+    /// Apply the next code only if the halfword at `address` equals
+    /// `value`: `(address, value)`
+    IfEqual16(u32, u16),
+    /// Apply the next code only if the halfword at `address` doesn't
+    /// equal `value`: `(address, value)`
+    IfNotEqual16(u32, u16),
+    /// Apply the next code only if the byte at `address` equals
+    /// `value`: `(address, value)`
+    IfEqual8(u32, u8),
+    /// Apply the next code only if the byte at `address` doesn't
+    /// equal `value`: `(address, value)`
+    IfNotEqual8(u32, u8),
+}
+
+impl Code {
+    fn from_words(addr: u32,
+                  value: u32,
+                  ram_len: usize) -> Result<Code, Error> {
+        let code_type = (addr >> 24) as u8;
+        let addr = addr & 0xffffff;
+
+        let code =
+            match code_type {
+                0x00 => {
+                    if addr as usize >= ram_len {
+                        return Err(Error::RamOffsetOutOfRange(addr));
+                    }
+
+                    Code::RamPoke(addr, try!(narrow(value, 0xff)) as u8)
+                }
+                0x30 => Code::Write8(addr, try!(narrow(value, 0xff)) as u8),
+                0x80 => Code::Write16(addr, try!(narrow(value, 0xffff)) as u16),
+                0x90 => Code::Write32(addr, value),
+                0xd0 => Code::IfEqual16(addr, try!(narrow(value, 0xffff)) as u16),
+                0xd1 => Code::IfNotEqual16(addr, try!(narrow(value, 0xffff)) as u16),
+                0xe0 => Code::IfEqual8(addr, try!(narrow(value, 0xff)) as u8),
+                0xe1 => Code::IfNotEqual8(addr, try!(narrow(value, 0xff)) as u8),
+                t => return Err(Error::UnknownType(t)),
+            };
+
+        Ok(code)
+    }
+}
+
+/// Make sure that `value` fits in `mask`
+fn narrow(value: u32, mask: u32) -> Result<u32, Error> {
+    if value & !mask == 0 {
+        Ok(value)
+    } else {
+        Err(Error::ValueTooWide(value))
+    }
+}
+
+/// Parse a cheat made of one or more codes. `ram_len` is the size of
+/// the emulated RAM.
+fn parse(cheat: &str, ram_len: usize) -> Result<Vec<Code>, Error> {
+    let mut digits = Vec::new();
+
+    for c in cheat.chars() {
+        if c.is_whitespace() || c == '+' {
+            continue;
+        }
+
+        match c.to_digit(16) {
+            Some(d) => digits.push(d),
+            None => return Err(Error::BadChar(c)),
+        }
+    }
+
+    if digits.is_empty() || digits.len() % 16 != 0 {
+        return Err(Error::BadLength(digits.len()));
+    }
+
+    let mut codes = Vec::with_capacity(digits.len() / 16);
+
+    for code in digits.chunks(16) {
+        let addr = code[0..8].iter().fold(0, |v, &d| (v << 4) | d);
+        let value = code[8..16].iter().fold(0, |v, &d| (v << 4) | d);
+
+        codes.push(try!(Code::from_words(addr, value, ram_len)));
+    }
+
+    Ok(codes)
+}
+
+#[derive(Debug)]
+/// Error returned when parsing an invalid cheat
+pub enum Error {
+    /// Encountered a character that's not an hexadecimal digit or a
+    /// separator
+    BadChar(char),
+    /// The number of hexadecimal digits is not a multiple of 16
+    BadLength(usize),
+    /// Unsupported code type
+    UnknownType(u8),
+    /// The value doesn't fit the width of the code
+    ValueTooWide(u32),
+    /// The offset of a RAM poke is past the end of the RAM
+    RamOffsetOutOfRange(u32),
+}
+
+#[test]
+fn test_parse() {
+    // Size of the PocketStation RAM
+    let ram_len = 2 * 1024;
+
+    assert_eq!(parse("30000010 00000063", ram_len).unwrap(),
+               vec![Code::Write8(0x10, 0x63)]);
+
+    assert_eq!(parse("D00001F0 00001234+800001F2 0000BEEF",
+                     ram_len).unwrap(),
+               vec![Code::IfEqual16(0x1f0, 0x1234),
+                    Code::Write16(0x1f2, 0xbeef)]);
+
+    assert_eq!(parse("000007ff000000aa 90000100deadbeef", ram_len).unwrap(),
+               vec![Code::RamPoke(0x7ff, 0xaa),
+                    Code::Write32(0x100, 0xdeadbeef)]);
+
+    assert!(parse("", ram_len).is_err());
+    assert!(parse("30000010 0000063", ram_len).is_err());
+    assert!(parse("30000010 00000163", ram_len).is_err());
+    assert!(parse("70000010 00000063", ram_len).is_err());
+    assert!(parse("3000001g 00000063", ram_len).is_err());
+    assert!(parse("00000800 00000001", ram_len).is_err());
+}
+
+#[test]
+fn test_set() {
+    let mut cheats = Cheats::new();
+
+    // The index comes from the frontend and can be arbitrarily large
+    cheats.set(::std::usize::MAX, true, "30000010 00000063", 0x800).unwrap();
+    cheats.set(3, true, "90000100 deadbeef", 0x800).unwrap();
+
+    assert_eq!(cheats.cheats.len(), 2);
+
+    cheats.set(::std::usize::MAX, false, "", 0x800).unwrap();
+
+    assert_eq!(cheats.cheats.keys().collect::<Vec<_>>(), [&3]);
+
+    assert!(cheats.set(4, true, "00000800 00000001", 0x800).is_err());
+    assert!(cheats.set(4, true, "00000800 00000001", 0x1000).is_ok());
+}
@@ -0,0 +1,597 @@
+//! Rendering of the PocketStation LCD for the frontend
+
+/// Colors used to render the LCD
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Palette {
+    /// XRGB8888 color of the pixels that are on
+    pub on: u32,
+    /// XRGB8888 color of the pixels that are off
+    pub off: u32,
+}
+
+/// Our original palette
+pub const WHITE_ON_BLACK: Palette = Palette {
+    on: 0xffffff,
+    off: 0x000000,
+};
+
+/// Approximation of the real LCD: dark pixels on a greenish-grey
+/// background
+pub const ORIGINAL: Palette = Palette {
+    on: 0x1e2619,
+    off: 0x9ca68c,
+};
+
+pub const HIGH_CONTRAST: Palette = Palette {
+    on: 0x000000,
+    off: 0xffffff,
+};
+
+/// `ORIGINAL` with the colors swapped
+pub const INVERTED: Palette = Palette {
+    on: ORIGINAL.off,
+    off: ORIGINAL.on,
+};
+
+impl Palette {
+    /// Return the color of a pixel whose intensity is `level`, from
+    /// 0.0 (off) to 1.0 (on)
+    pub fn color(&self, level: f32) -> u32 {
+        let mut color = 0;
+
+        for &shift in &[0, 8, 16] {
+            let on = ((self.on >> shift) & 0xff) as f32;
+            let off = ((self.off >> shift) & 0xff) as f32;
+
+            let c = off + (on - off) * level;
+
+            color |= (c.round() as u32) << shift;
+        }
+
+        color
+    }
+}
+
+/// Model of the LCD pixel response. The LCD can be sampled several
+/// times per frame, the intensity of each pixel is then the fraction of
+/// the samples where it was on. This way the shades of grey obtained by
+/// quickly toggling pixels are rendered instead of strobing. On top of
+/// that the output can be blended with the previous frames to simulate
+/// the slow response of the LCD.
+pub struct Renderer {
+    /// Intensity of each pixel in the last frame rendered
+    levels: Vec<f32>,
+    /// Number of samples where each pixel was on
+    on_samples: Vec<u16>,
+    /// Number of samples taken since the last frame was rendered
+    samples: u16,
+    /// Fraction of the previous intensity of the pixels kept every
+    /// frame, 0.0 disables ghosting
+    decay: f32,
+    /// How the samples taken during a frame are combined
+    combine: Combine,
+}
+
+impl Renderer {
+    pub fn new() -> Renderer {
+        Renderer {
+            levels: vec![0.; LCD_PIXELS],
+            on_samples: vec![0; LCD_PIXELS],
+            samples: 0,
+            decay: 0.,
+            combine: Combine::Average,
+        }
+    }
+
+    pub fn set_decay(&mut self, decay: f32) {
+        self.decay = decay;
+    }
+
+    pub fn set_combine(&mut self, combine: Combine) {
+        self.combine = combine;
+    }
+
+    /// Return true if the LCD has been sampled since the last frame
+    /// was rendered
+    pub fn has_samples(&self) -> bool {
+        self.samples > 0
+    }
+
+    /// Drop the samples taken since the last frame was rendered
+    pub fn clear_samples(&mut self) {
+        for s in &mut self.on_samples {
+            *s = 0;
+        }
+
+        self.samples = 0;
+    }
+
+    /// Add a sample of the LCD contents. `rows` contains one `u32`
+    /// per row, bit `x` of row `y` is the pixel at `(x, y)` and is 0
+    /// if the pixel is on.
+    pub fn sample(&mut self, rows: &[u32; 32]) {
+        if self.combine == Combine::LastWriteWins {
+            self.clear_samples();
+        }
+
+        for (y, &row) in rows.iter().enumerate() {
+            for x in 0..32 {
+                if (row >> x) & 1 == 0 {
+                    self.on_samples[y * 32 + x] += 1;
+                }
+            }
+        }
+
+        self.samples += 1;
+    }
+
+    /// Combine the samples with the previous frames and render the
+    /// result using `palette`. The samples are cleared. If there are
+    /// none the previous frame is rendered again.
+    pub fn render(&mut self, palette: &Palette) -> [u32; LCD_PIXELS] {
+        let mut fb_out = [palette.off; LCD_PIXELS];
+
+        let samples = self.samples as f32;
+
+        for (off, level) in self.levels.iter_mut().enumerate() {
+            if samples > 0. {
+                let target = self.on_samples[off] as f32 / samples;
+
+                *level = target + (*level - target) * self.decay;
+            }
+
+            fb_out[off] = palette.color(*level);
+        }
+
+        self.clear_samples();
+
+        fb_out
+    }
+}
+
+/// Method used to combine the samples taken during a frame
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Combine {
+    /// The intensity of each pixel is the fraction of the samples
+    /// where it was on
+    Average,
+    /// Only the last sample is displayed
+    LastWriteWins,
+}
+
+/// Number of pixels on the LCD
+pub const LCD_PIXELS: usize = 32 * 32;
+
+/// Layout of the video output
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Layout {
+    /// Size of the LCD pixels in output pixels
+    pub scale: u32,
+    /// If true the LCD pixels are separated by a thin gap. Only used
+    /// when `scale` is at least 3, there's not enough room otherwise.
+    pub gaps: bool,
+    /// If true the screen is drawn inside a PocketStation shell
+    pub shell: bool,
+    /// Rotation applied to the whole output, shell included
+    pub rotation: Rotation,
+}
+
+impl Layout {
+    /// Return the size of the output in pixels
+    pub fn size(&self) -> (u32, u32) {
+        let (w, h) =
+            if self.shell {
+                (SHELL_WIDTH, SHELL_HEIGHT)
+            } else {
+                (32, 32)
+            };
+
+        let (w, h) = (w * self.scale, h * self.scale);
+
+        if self.rotation.is_sideways() {
+            (h, w)
+        } else {
+            (w, h)
+        }
+    }
+
+    /// Draw the output frame for the LCD image `lcd`, as returned by
+    /// `Renderer::render`
+    pub fn draw(&self,
+                lcd: &[u32; LCD_PIXELS],
+                palette: &Palette,
+                buttons: &Buttons) -> Vec<u32> {
+        let upright = Layout {
+            rotation: Rotation::Deg0,
+            ..*self
+        };
+
+        let (width, height) = upright.size();
+
+        let out =
+            if self.scale == 1 && !self.shell {
+                lcd.to_vec()
+            } else {
+                upright.draw_upright(lcd, palette, buttons)
+            };
+
+        rotate(&out, width, height, self.rotation)
+    }
+
+    /// Draw the output without rotation
+    fn draw_upright(&self,
+                    lcd: &[u32; LCD_PIXELS],
+                    palette: &Palette,
+                    buttons: &Buttons) -> Vec<u32> {
+        let (width, height) = self.size();
+
+        let mut canvas = Canvas {
+            pixels: vec![0; (width * height) as usize],
+            width: width,
+            height: height,
+        };
+
+        let s = self.scale;
+
+        let (x0, y0) =
+            if self.shell {
+                self.draw_shell(&mut canvas, buttons);
+                (SCREEN_X * s, SCREEN_Y * s)
+            } else {
+                (0, 0)
+            };
+
+        canvas.fill_rect(x0, y0, 32 * s, 32 * s, palette.off);
+
+        let gap =
+            if self.gaps && s >= 3 {
+                (s / 8).max(1)
+            } else {
+                0
+            };
+
+        for y in 0..32 {
+            for x in 0..32 {
+                let color = lcd[(y * 32 + x) as usize];
+
+                canvas.fill_rect(x0 + x * s,
+                                 y0 + y * s,
+                                 s - gap,
+                                 s - gap,
+                                 color);
+            }
+        }
+
+        canvas.pixels
+    }
+
+    fn draw_shell(&self, canvas: &mut Canvas, buttons: &Buttons) {
+        let s = self.scale;
+
+        canvas.fill_rounded_rect(0,
+                                 0,
+                                 SHELL_WIDTH * s,
+                                 SHELL_HEIGHT * s,
+                                 SHELL_CORNER_RADIUS * s,
+                                 SHELL_COLOR);
+
+        // Frame around the screen
+        canvas.fill_rect((SCREEN_X - BEZEL_WIDTH) * s,
+                         (SCREEN_Y - BEZEL_WIDTH) * s,
+                         (32 + BEZEL_WIDTH * 2) * s,
+                         (32 + BEZEL_WIDTH * 2) * s,
+                         BEZEL_COLOR);
+
+        let color = |pressed| {
+            if pressed {
+                BUTTON_LIT_COLOR
+            } else {
+                BUTTON_COLOR
+            }
+        };
+
+        let pad = [(buttons.up, DPAD_X, DPAD_Y - BUTTON_SIZE - 1),
+                   (buttons.down, DPAD_X, DPAD_Y + BUTTON_SIZE + 1),
+                   (buttons.left, DPAD_X - BUTTON_SIZE - 1, DPAD_Y),
+                   (buttons.right, DPAD_X + BUTTON_SIZE + 1, DPAD_Y)];
+
+        for &(pressed, x, y) in &pad {
+            canvas.fill_rect(x * s,
+                             y * s,
+                             BUTTON_SIZE * s,
+                             BUTTON_SIZE * s,
+                             color(pressed));
+        }
+
+        canvas.fill_rounded_rect(ACTION_X * s,
+                                 ACTION_Y * s,
+                                 ACTION_SIZE * s,
+                                 ACTION_SIZE * s,
+                                 ACTION_SIZE * s / 2,
+                                 color(buttons.action));
+    }
+}
+
+/// Clockwise rotation of the output
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Rotation {
+    Deg0,
+    Deg90,
+    Deg180,
+    Deg270,
+}
+
+impl Rotation {
+    /// Return true if the rotation swaps the width and height
+    pub fn is_sideways(self) -> bool {
+        self == Rotation::Deg90 || self == Rotation::Deg270
+    }
+}
+
+/// Rotate the `width`x`height` image `pixels`
+fn rotate(pixels: &[u32],
+          width: u32,
+          height: u32,
+          rotation: Rotation) -> Vec<u32> {
+    if rotation == Rotation::Deg0 {
+        return pixels.to_vec();
+    }
+
+    let (w, h) = (width as usize, height as usize);
+
+    let mut out = vec![0; w * h];
+
+    for y in 0..h {
+        for x in 0..w {
+            let off =
+                match rotation {
+                    Rotation::Deg0 => y * w + x,
+                    Rotation::Deg90 => x * h + (h - 1 - y),
+                    Rotation::Deg180 => (h - 1 - y) * w + (w - 1 - x),
+                    Rotation::Deg270 => (w - 1 - x) * h + y,
+                };
+
+            out[off] = pixels[y * w + x];
+        }
+    }
+
+    out
+}
+
+/// State of the PocketStation buttons, used to light them up on the
+/// drawn shell
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Buttons {
+    pub action: bool,
+    pub up: bool,
+    pub down: bool,
+    pub left: bool,
+    pub right: bool,
+}
+
+/// Output image being drawn
+struct Canvas {
+    pixels: Vec<u32>,
+    width: u32,
+    height: u32,
+}
+
+impl Canvas {
+    fn fill_rect(&mut self, x: u32, y: u32, w: u32, h: u32, color: u32) {
+        if x >= self.width {
+            return;
+        }
+
+        let x_end = (x + w).min(self.width);
+        let y_end = (y + h).min(self.height);
+
+        for py in y..y_end {
+            let line = (py * self.width) as usize;
+
+            let start = line + x as usize;
+            let end = line + x_end as usize;
+
+            for p in &mut self.pixels[start..end] {
+                *p = color;
+            }
+        }
+    }
+
+    /// Fill a rectangle whose corners are rounded with radius `r`
+    fn fill_rounded_rect(&mut self,
+                         x: u32,
+                         y: u32,
+                         w: u32,
+                         h: u32,
+                         r: u32,
+                         color: u32) {
+        let r = r.min(w / 2).min(h / 2) as i64;
+
+        for py in 0..h {
+            for px in 0..w {
+                // Distance to the center of the nearest corner circle,
+                // 0 if we're not in a corner
+                let dx = (r - px as i64).max(px as i64 - (w as i64 - 1 - r))
+                    .max(0);
+                let dy = (r - py as i64).max(py as i64 - (h as i64 - 1 - r))
+                    .max(0);
+
+                if dx * dx + dy * dy > r * r {
+                    continue;
+                }
+
+                let (cx, cy) = (x + px, y + py);
+
+                if cx < self.width && cy < self.height {
+                    self.pixels[(cy * self.width + cx) as usize] = color;
+                }
+            }
+        }
+    }
+}
+
+// Layout of the shell, in LCD pixels
+
+const SHELL_WIDTH: u32 = 48;
+const SHELL_HEIGHT: u32 = 72;
+const SHELL_CORNER_RADIUS: u32 = 6;
+/// Top-left corner of the screen
+const SCREEN_X: u32 = 8;
+const SCREEN_Y: u32 = 8;
+const BEZEL_WIDTH: u32 = 2;
+/// Top-left corner of the empty center cell of the directional pad,
+/// the four buttons are around it
+const DPAD_X: u32 = 12;
+const DPAD_Y: u32 = 55;
+const BUTTON_SIZE: u32 = 5;
+/// Top-left corner of the round action button
+const ACTION_X: u32 = 31;
+const ACTION_Y: u32 = 53;
+const ACTION_SIZE: u32 = 9;
+
+const SHELL_COLOR: u32 = 0xb4b6b0;
+const BEZEL_COLOR: u32 = 0x303234;
+const BUTTON_COLOR: u32 = 0x6c6e70;
+const BUTTON_LIT_COLOR: u32 = 0xffc040;
+
+#[test]
+fn test_renderer() {
+    let palette = Palette { on: 0xff8000, off: 0x000000 };
+
+    assert_eq!(palette.color(0.), 0x000000);
+    assert_eq!(palette.color(1.), 0xff8000);
+    assert_eq!(palette.color(0.5), 0x804000);
+
+    // Only the top left pixel is on
+    let mut rows = [!0u32; 32];
+    rows[0] = !1;
+
+    let mut renderer = Renderer::new();
+
+    renderer.sample(&rows);
+
+    let out = renderer.render(&palette);
+
+    assert_eq!(out[0], 0xff8000);
+    assert!(out[1..].iter().all(|&c| c == 0));
+
+    // No new sample, the previous frame is rendered again
+    let out = renderer.render(&palette);
+
+    assert_eq!(out[0], 0xff8000);
+
+    // The pixel is on in half the samples
+    renderer.sample(&rows);
+    renderer.sample(&[!0; 32]);
+
+    let out = renderer.render(&palette);
+
+    assert_eq!(out[0], 0x804000);
+
+    renderer.set_combine(Combine::LastWriteWins);
+
+    renderer.sample(&[!0; 32]);
+    renderer.sample(&rows);
+
+    assert_eq!(renderer.render(&palette)[0], 0xff8000);
+
+    renderer.set_combine(Combine::Average);
+
+    // Ghosting, the pixel fades out
+    renderer.sample(&rows);
+    renderer.render(&palette);
+
+    renderer.set_decay(0.5);
+
+    renderer.sample(&[!0; 32]);
+    assert_eq!(renderer.render(&palette)[0], 0x804000);
+
+    renderer.sample(&[!0; 32]);
+    assert_eq!(renderer.render(&palette)[0], 0x402000);
+}
+
+#[test]
+fn test_layout() {
+    let palette = Palette { on: 0xffffff, off: 0x000000 };
+
+    let mut lcd = [0; LCD_PIXELS];
+    lcd[0] = 0xffffff;
+
+    let layout = Layout {
+        scale: 4,
+        gaps: true,
+        shell: false,
+        rotation: Rotation::Deg0,
+    };
+
+    assert_eq!(layout.size(), (128, 128));
+
+    let out = layout.draw(&lcd, &palette, &Buttons::default());
+
+    assert_eq!(out.len(), 128 * 128);
+    // The first pixel is a 3x3 square followed by a gap
+    assert_eq!(&out[0..5], &[0xffffff, 0xffffff, 0xffffff, 0, 0]);
+    assert_eq!(out[3 * 128], 0);
+
+    let mut layout = Layout {
+        scale: 2,
+        gaps: false,
+        shell: true,
+        rotation: Rotation::Deg0,
+    };
+
+    let (width, height) = layout.size();
+
+    let mut buttons = Buttons::default();
+
+    let off = layout.draw(&lcd, &palette, &buttons);
+
+    buttons.action = true;
+
+    let on = layout.draw(&lcd, &palette, &buttons);
+
+    assert_eq!(off.len(), (width * height) as usize);
+
+    // The corners are rounded
+    assert_eq!(on[0], 0);
+
+    // Top-left pixel of the screen
+    let screen = (SCREEN_Y * 2 * width + SCREEN_X * 2) as usize;
+    assert_eq!(on[screen], 0xffffff);
+
+    // Center of the action button
+    let action = (ACTION_Y + ACTION_SIZE / 2) * 2 * width +
+        (ACTION_X + ACTION_SIZE / 2) * 2;
+    assert_eq!(off[action as usize], BUTTON_COLOR);
+    assert_eq!(on[action as usize], BUTTON_LIT_COLOR);
+
+    // The whole device is rotated
+    layout.rotation = Rotation::Deg90;
+
+    assert_eq!(layout.size(), (height, width));
+
+    let rotated = layout.draw(&lcd, &palette, &buttons);
+
+    // The top-left pixel of the screen is now its top-right pixel
+    let screen = SCREEN_X * 2 * height + (height - 1 - SCREEN_Y * 2);
+    assert_eq!(rotated[screen as usize], 0xffffff);
+}
+
+#[test]
+fn test_rotate() {
+    // 3x2 image
+    let image = [1, 2, 3,
+                 4, 5, 6];
+
+    assert_eq!(rotate(&image, 3, 2, Rotation::Deg90),
+               [4, 1,
+                5, 2,
+                6, 3]);
+    assert_eq!(rotate(&image, 3, 2, Rotation::Deg180),
+               [6, 5, 4,
+                3, 2, 1]);
+    assert_eq!(rotate(&image, 3, 2, Rotation::Deg270),
+               [3, 6,
+                2, 5,
+                1, 4]);
+}
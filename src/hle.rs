@@ -0,0 +1,497 @@
+//! High level emulation of the PocketStation BIOS, used when no BIOS
+//! dump can be found.
+//!
+//! We can't modify the CPU registers from outside so the HLE BIOS is
+//! a tiny ARM kernel (`ROM`) loaded in place of the real BIOS. It
+//! sets up the exception vectors and stacks and contains a few "trap"
+//! instructions: when the CPU is about to execute one of them
+//! `Hle::pc_change` takes over and implements the actual
+//! functionality in Rust. Values are passed back to the ARM code
+//! through a mailbox word in RAM.
+//!
+//! All the HLE state lives in the kernel area of the RAM so that it's
+//! saved alongside the rest of the emulator state in savestates.
+//!
+//! Only a subset of the kernel calls is implemented: SetCallbacks,
+//! SetCpuSpeed, PrepareExecute, DoExecute and GetDirIndex. Known gaps
+//! are the date and time functions, the physical flash writes, the
+//! communication (IR and memory card slot) functions and the snapshot
+//! support of DoExecute. Unimplemented SWIs are logged and leave r0
+//! untouched. IRQs are forwarded to the callback installed with
+//! SetCallbacks, the kernel doesn't handle any of them itself.
+
+use pockystation::cpu::Cpu;
+use pockystation::debugger::Debugger as DebuggerInterface;
+use pockystation::memory::{HalfWord, Word};
+use pockystation::memory::bios::BIOS_SIZE;
+
+use debugger::Debugger;
+use memcard::{BLOCK_SIZE, FRAME_SIZE};
+use memcard::directory::{Directory, Save};
+
+/// HLE BIOS state
+#[derive(Clone)]
+pub struct Hle {
+    /// Unimplemented SWIs we've already warned about
+    unimplemented: Vec<u32>,
+    /// True if we've already warned about an IRQ without callback
+    irq_warned: bool,
+    /// Directory filename of the application to boot, `None` to boot
+    /// the first one on the card
+    boot_app: Option<String>,
+}
+
+impl Hle {
+    pub fn new() -> Hle {
+        Hle {
+            unimplemented: Vec::new(),
+            irq_warned: false,
+            boot_app: None,
+        }
+    }
+
+    /// Select the application booted on reset
+    pub fn set_boot_app(&mut self, boot_app: Option<String>) {
+        self.boot_app = boot_app;
+    }
+
+    /// Called before every instruction, must be fast
+    pub fn pc_change(&mut self, cpu: &mut Cpu) {
+        let pc = cpu.current_pc();
+
+        if pc >> 24 != ROM_BASE >> 24 {
+            return;
+        }
+
+        match pc - ROM_BASE {
+            BOOT_TRAP => self.boot(cpu),
+            SWI_TRAP => self.swi(cpu),
+            IRQ_TRAP => self.irq(cpu),
+            FIQ_TRAP => warn!("HLE BIOS: unexpected FIQ, disabling FIQs"),
+            UNDEF_TRAP =>
+                warn!("HLE BIOS: undefined instruction at 0x{:08x}",
+                      cpu.registers()[14].wrapping_sub(4)),
+            ABORT_TRAP =>
+                error!("HLE BIOS: memory abort, return address 0x{:08x}. \
+                        Halting.", cpu.registers()[14]),
+            _ => (),
+        }
+    }
+
+    /// Boot the application requested with `PrepareExecute`, the one
+    /// selected with `set_boot_app` or the first one on the card
+    fn boot(&mut self, cpu: &mut Cpu) {
+        let requested = cpu.examine::<Word>(PENDING_APP);
+
+        store(cpu, PENDING_APP, 0);
+        store(cpu, CURRENT_APP, 0);
+
+        // Callbacks belong to the previous application
+        for i in 0..CALLBACK_COUNT {
+            store(cpu, CALLBACKS + i * 4, 0);
+        }
+
+        let dir =
+            match Directory::parse(cpu.interconnect().flash().data()) {
+                Ok(d) => d,
+                Err(e) => {
+                    error!("HLE BIOS: can't parse the card directory: {:?}", e);
+                    store(cpu, MAILBOX, ROM_BASE + IDLE);
+                    return;
+                }
+            };
+
+        let save =
+            if requested != 0 {
+                dir.executables().find(|s| s.first_block() == requested as usize)
+            } else if let Some(ref filename) = self.boot_app {
+                let save = dir.executables().find(|s| s.filename == *filename);
+
+                if save.is_none() {
+                    warn!("HLE BIOS: application {} not found on the card",
+                          filename);
+                }
+
+                save.or_else(|| dir.executables().next())
+            } else {
+                dir.executables().next()
+            };
+
+        let entry =
+            match save {
+                Some(save) => {
+                    let entry = map_executable(cpu, save);
+
+                    if entry.is_some() {
+                        store(cpu, CURRENT_APP, save.first_block() as u32);
+                    }
+
+                    entry
+                }
+                None => {
+                    error!("HLE BIOS: no PocketStation application to boot");
+                    None
+                }
+            };
+
+        store(cpu, MAILBOX, entry.unwrap_or(ROM_BASE + IDLE));
+    }
+
+    /// Handle a SWI kernel call
+    fn swi(&mut self, cpu: &mut Cpu) {
+        let regs = *cpu.registers();
+
+        // Return address
+        let lr = regs[14];
+        // The ARM code copies the SPSR in r12 before the trap
+        let spsr = regs[12];
+
+        // The SWI can be called from ARM or Thumb code, the comment
+        // field is 8 bits long in Thumb state and 24 bits long in ARM
+        // state
+        let number =
+            if spsr & PSR_THUMB != 0 {
+                cpu.examine::<HalfWord>(lr.wrapping_sub(2)) & 0xff
+            } else {
+                cpu.examine::<Word>(lr.wrapping_sub(4)) & 0xffffff
+            };
+
+        // By default r0 is left untouched
+        let mut ret = regs[0];
+
+        // See the "Pocketstation SWI Function Summary" in nocash's
+        // PSX specifications (psx-spx) for the function numbers
+        match number {
+            // PrepareExecute(flags, dir_index, param)
+            0x08 => {
+                let block = regs[1];
+
+                let valid =
+                    match Directory::parse(cpu.interconnect().flash().data()) {
+                        Ok(dir) =>
+                            dir.executables()
+                            .any(|s| s.first_block() == block as usize),
+                        Err(_) => false,
+                    };
+
+                if valid {
+                    store(cpu, PENDING_APP, block);
+                    ret = 1;
+                } else {
+                    warn!("HLE BIOS: PrepareExecute on block {} which \
+                           doesn't contain an application", block);
+                    ret = 0;
+                }
+            }
+            // DoExecute(snapshot_flag)
+            0x09 => {
+                if cpu.examine::<Word>(PENDING_APP) != 0 {
+                    // Reinitialize the stacks and go through the
+                    // boot sequence again
+                    cpu.set_pc(ROM_BASE + STACK_SETUP);
+                    return;
+                }
+
+                warn!("HLE BIOS: DoExecute without PrepareExecute");
+                ret = 0;
+            }
+            // GetDirIndex(), returns the directory index of the
+            // running application, 0 if none
+            0x0d => ret = cpu.examine::<Word>(CURRENT_APP),
+            // SetCallbacks(index, proc)
+            0x01 => {
+                let index = regs[0];
+
+                if index < CALLBACK_COUNT {
+                    let addr = CALLBACKS + index * 4;
+
+                    ret = cpu.examine::<Word>(addr);
+                    store(cpu, addr, regs[1]);
+                } else {
+                    warn!("HLE BIOS: SetCallbacks with invalid index {}",
+                          index);
+                    ret = 0;
+                }
+            }
+            // SetCpuSpeed(speed), returns the previous speed
+            0x02 => {
+                let speed = regs[0] & 0xf;
+
+                ret = cpu.examine::<Word>(CLK_MODE) & 0xf;
+                store(cpu, CLK_MODE, speed);
+            }
+            n => {
+                if self.unimplemented.contains(&n) {
+                    debug!("HLE BIOS: unimplemented SWI 0x{:02x}", n);
+                } else {
+                    warn!("HLE BIOS: unimplemented SWI 0x{:02x} \
+                           (r0=0x{:08x} r1=0x{:08x} r2=0x{:08x} r3=0x{:08x}) \
+                           called from 0x{:08x}",
+                          n, regs[0], regs[1], regs[2], regs[3], lr);
+                    self.unimplemented.push(n);
+                }
+            }
+        }
+
+        store(cpu, MAILBOX, ret);
+    }
+
+    /// Handle an IRQ by calling the IRQ callback registered with
+    /// `SetCallbacks`, it's up to the application to acknowledge the
+    /// interrupts. If there's none nobody is going to acknowledge
+    /// them so we mask them to avoid an IRQ storm.
+    fn irq(&mut self, cpu: &mut Cpu) {
+        let callback = cpu.examine::<Word>(CALLBACKS + IRQ_CALLBACK * 4);
+
+        if callback == 0 {
+            let pending =
+                cpu.examine::<Word>(INT_LATCH) &
+                cpu.examine::<Word>(INT_MASK_READ);
+
+            if !self.irq_warned {
+                warn!("HLE BIOS: IRQ 0x{:08x} without callback, masking it",
+                      pending);
+                self.irq_warned = true;
+            }
+
+            store(cpu, INT_MASK_CLR, pending);
+        }
+
+        store(cpu, MAILBOX, callback);
+    }
+}
+
+/// Debugger hooks called by the CPU, forwarding to the debugger and
+/// the HLE BIOS if they're in use
+pub struct Hooks<'a> {
+    pub debugger: Option<&'a mut Debugger>,
+    pub hle: Option<&'a mut Hle>,
+}
+
+impl<'a> DebuggerInterface for Hooks<'a> {
+    fn trigger_break(&mut self) {
+        if let Some(ref mut debugger) = self.debugger {
+            debugger.trigger_break();
+        }
+    }
+
+    fn pc_change(&mut self, cpu: &mut Cpu) {
+        if let Some(ref mut hle) = self.hle {
+            hle.pc_change(cpu);
+        }
+
+        if let Some(ref mut debugger) = self.debugger {
+            debugger.pc_change(cpu);
+        }
+    }
+
+    fn memory_read(&mut self, cpu: &mut Cpu, addr: u32) {
+        if let Some(ref mut debugger) = self.debugger {
+            debugger.memory_read(cpu, addr);
+        }
+    }
+
+    fn memory_write(&mut self, cpu: &mut Cpu, addr: u32) {
+        if let Some(ref mut debugger) = self.debugger {
+            debugger.memory_write(cpu, addr);
+        }
+    }
+}
+
+/// Build the HLE BIOS image
+pub fn rom() -> Vec<u8> {
+    let mut rom = vec![0; BIOS_SIZE as usize];
+
+    for (i, w) in ROM.iter().enumerate() {
+        for b in 0..4 {
+            rom[i * 4 + b] = (w >> (b * 8)) as u8;
+        }
+    }
+
+    rom
+}
+
+/// Map the blocks of `save` in the virtual flash window and return
+/// the application's entry point
+fn map_executable(cpu: &mut Cpu, save: &Save) -> Option<u32> {
+    let nblocks = save.blocks.len() as u32;
+
+    for (i, &block) in save.blocks.iter().enumerate() {
+        store(cpu, F_BANK_VAL + i as u32 * 4, block as u32);
+    }
+
+    store(cpu, F_BANK_FLG, (1 << nblocks) - 1);
+
+    // The executable header follows the title frame and the icon
+    // frames, it starts with the entry point
+    let header = (1 + save.icon.frames.len()) * FRAME_SIZE;
+
+    let data = cpu.interconnect().flash().data();
+    let off = save.first_block() * BLOCK_SIZE + header;
+
+    let entry =
+        (data[off] as u32) |
+        ((data[off + 1] as u32) << 8) |
+        ((data[off + 2] as u32) << 16) |
+        ((data[off + 3] as u32) << 24);
+
+    let end = VIRTUAL_FLASH_BASE + nblocks * BLOCK_SIZE as u32;
+
+    if entry < VIRTUAL_FLASH_BASE || entry >= end || entry & 3 != 0 {
+        error!("HLE BIOS: bad entry point 0x{:08x} for {}",
+               entry, save.filename);
+        return None;
+    }
+
+    info!("HLE BIOS: booting {} \"{}\" at 0x{:08x}",
+          save.filename, save.title, entry);
+
+    Some(entry)
+}
+
+fn store(cpu: &mut Cpu, addr: u32, val: u32) {
+    cpu.interconnect_mut().store::<Word>(addr, val);
+}
+
+/// Base address of the BIOS in the CPU address space
+const ROM_BASE: u32 = 0x04000000;
+
+/// Base address of the virtual flash window where applications are
+/// mapped
+const VIRTUAL_FLASH_BASE: u32 = 0x02000000;
+/// Virtual flash bank enable register, one bit per block
+const F_BANK_FLG: u32 = 0x06000008;
+/// Virtual flash bank mapping registers, one word per block
+/// containing the physical block number
+const F_BANK_VAL: u32 = 0x06000100;
+
+/// Latched interrupt requests
+const INT_LATCH: u32 = 0x0a000000;
+/// Interrupt mask (read)
+const INT_MASK_READ: u32 = 0x0a000008;
+/// Interrupt mask clear (write)
+const INT_MASK_CLR: u32 = 0x0a00000c;
+
+/// CPU clock control register, the low 4 bits contain the speed
+const CLK_MODE: u32 = 0x0b800000;
+
+/// Word in RAM used to pass values from the HLE code to the ARM code
+const MAILBOX: u32 = 0x1fc;
+/// Block number of the application requested with `PrepareExecute`,
+/// 0 if none
+const PENDING_APP: u32 = 0x1f8;
+/// Block number (directory index) of the running application, 0 if
+/// none
+const CURRENT_APP: u32 = 0x1f4;
+/// Callbacks registered with `SetCallbacks`
+const CALLBACKS: u32 = 0x1c0;
+/// Number of entries in the callback table
+const CALLBACK_COUNT: u32 = 8;
+/// Index of the IRQ callback in the callback table
+const IRQ_CALLBACK: u32 = 1;
+
+/// Thumb state bit in the CPSR and SPSR
+const PSR_THUMB: u32 = 1 << 5;
+
+/// Offsets of the traps and entry points in `ROM`
+const RESET: u32 = 0x40;
+const STACK_SETUP: u32 = 0x5c;
+const BOOT_TRAP: u32 = 0x74;
+const IDLE: u32 = 0x8c;
+const SWI_HANDLER: u32 = 0x90;
+const SWI_TRAP: u32 = 0x98;
+const IRQ_HANDLER: u32 = 0xac;
+const IRQ_TRAP: u32 = 0xb4;
+const FIQ_TRAP: u32 = 0xd0;
+const UNDEF_TRAP: u32 = 0xe4;
+const ABORT_TRAP: u32 = 0xec;
+
+/// HLE kernel code. The `mov r0, r0` instructions are the traps
+/// handled in `Hle::pc_change`.
+const ROM: [u32; 61] = [
+    // 0x00: Exception vectors, copied to RAM on reset
+    0xe59ff018, // ldr   pc, [pc, #0x18]   ; Reset
+    0xe59ff018, // ldr   pc, [pc, #0x18]   ; Undefined instruction
+    0xe59ff018, // ldr   pc, [pc, #0x18]   ; SWI
+    0xe59ff018, // ldr   pc, [pc, #0x18]   ; Prefetch abort
+    0xe59ff018, // ldr   pc, [pc, #0x18]   ; Data abort
+    0xe59ff018, // ldr   pc, [pc, #0x18]   ; Reserved
+    0xe59ff018, // ldr   pc, [pc, #0x18]   ; IRQ
+    0xe59ff018, // ldr   pc, [pc, #0x18]   ; FIQ
+    ROM_BASE + RESET,
+    ROM_BASE + UNDEF_TRAP,
+    ROM_BASE + SWI_HANDLER,
+    ROM_BASE + ABORT_TRAP,
+    ROM_BASE + ABORT_TRAP,
+    ROM_BASE + ABORT_TRAP,
+    ROM_BASE + IRQ_HANDLER,
+    ROM_BASE + FIQ_TRAP,
+    // 0x40: Reset
+    0xe3a00301, // mov   r0, #0x04000000
+    0xe3a01000, // mov   r1, #0
+    0xe3a02040, // mov   r2, #0x40
+    0xe4903004, // ldr   r3, [r0], #4
+    0xe4813004, // str   r3, [r1], #4
+    0xe2522004, // subs  r2, r2, #4
+    0x1afffffb, // bne   0x4c
+    // 0x5c: Stack setup
+    0xe321f0d2, // msr   cpsr_c, #0xd2     ; IRQ mode
+    0xe3a0dd06, // mov   sp, #0x180
+    0xe321f0df, // msr   cpsr_c, #0xdf     ; System mode
+    0xe3a0db02, // mov   sp, #0x800
+    0xe321f0d3, // msr   cpsr_c, #0xd3     ; Supervisor mode
+    0xe3a0dd07, // mov   sp, #0x1c0
+    // 0x74: Boot
+    0xe1a00000, // mov   r0, r0            ; BOOT_TRAP
+    0xe3a00f7f, // mov   r0, #0x1fc
+    0xe590e000, // ldr   lr, [r0]
+    0xe3a00010, // mov   r0, #0x10
+    0xe16ff000, // msr   spsr_fsxc, r0     ; User mode, IRQ enabled
+    0xe1b0f00e, // movs  pc, lr
+    // 0x8c: Idle loop
+    0xeafffffe, // b     0x8c
+    // 0x90: SWI
+    0xe92d1000, // stmfd sp!, {r12}
+    0xe14fc000, // mrs   r12, spsr
+    0xe1a00000, // mov   r0, r0            ; SWI_TRAP
+    0xe8bd1000, // ldmfd sp!, {r12}
+    0xe3a00f7f, // mov   r0, #0x1fc
+    0xe5900000, // ldr   r0, [r0]
+    0xe1b0f00e, // movs  pc, lr
+    // 0xac: IRQ
+    0xe24ee004, // sub   lr, lr, #4
+    0xe92d500f, // stmfd sp!, {r0-r3, r12, lr}
+    0xe1a00000, // mov   r0, r0            ; IRQ_TRAP
+    0xe3a00f7f, // mov   r0, #0x1fc
+    0xe5900000, // ldr   r0, [r0]
+    0xe3500000, // cmp   r0, #0
+    0x11a0e00f, // movne lr, pc
+    0x112fff10, // bxne  r0                ; ARM or Thumb callback
+    0xe8fd900f, // ldmfd sp!, {r0-r3, r12, pc}^
+    // 0xd0: FIQ
+    0xe1a00000, // mov   r0, r0            ; FIQ_TRAP
+    0xe14f8000, // mrs   r8, spsr
+    0xe3888040, // orr   r8, r8, #0x40     ; Disable FIQs on return
+    0xe161f008, // msr   spsr_c, r8
+    0xe25ef004, // subs  pc, lr, #4
+    // 0xe4: Undefined instruction
+    0xe1a00000, // mov   r0, r0            ; UNDEF_TRAP
+    0xe1b0f00e, // movs  pc, lr
+    // 0xec: Aborts
+    0xe1a00000, // mov   r0, r0            ; ABORT_TRAP
+    0xeafffffe, // b     0xf0
+];
+
+#[test]
+fn test_rom_traps() {
+    let nop = 0xe1a00000;
+
+    for &trap in &[BOOT_TRAP, SWI_TRAP, IRQ_TRAP, FIQ_TRAP, UNDEF_TRAP, ABORT_TRAP] {
+        assert_eq!(ROM[trap as usize / 4], nop);
+    }
+
+    let rom = rom();
+
+    assert_eq!(rom.len(), BIOS_SIZE as usize);
+    assert_eq!(&rom[RESET as usize..RESET as usize + 4], &[0x01, 0x03, 0xa0, 0xe3]);
+}
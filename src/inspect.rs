@@ -0,0 +1,1073 @@
+//! Savestate inspection: build a tree of named structs and fields out
+//! of a savestate and compare two of them field by field.
+
+use std::io;
+use std::fmt;
+use std::collections::{HashMap, HashSet};
+
+use serde::ser;
+use serde::ser::Serialize;
+
+use rustc_serialize::Encodable;
+
+use savestate;
+use savestate::Error;
+
+/// Node of the tree describing a savestate
+#[derive(Clone, Debug, PartialEq)]
+pub struct Node {
+    /// Value of leaves, type or variant name otherwise
+    pub label: String,
+    /// Named children, empty for leaves. Sequence elements are named
+    /// `[index]`.
+    pub children: Vec<(String, Node)>,
+}
+
+impl Node {
+    fn leaf<T: ToString>(v: T) -> Node {
+        Node {
+            label: v.to_string(),
+            children: Vec::new(),
+        }
+    }
+
+    pub fn is_leaf(&self) -> bool {
+        self.children.is_empty()
+    }
+
+    /// Return true if the node is a sequence of leaves, we dump those
+    /// in rows to keep memory buffers readable
+    fn is_array(&self) -> bool {
+        !self.children.is_empty() &&
+            self.children.iter()
+            .all(|&(ref n, ref c)| n.starts_with('[') && c.is_leaf())
+    }
+
+    /// Print the tree under this node, `name` is the name of the node
+    pub fn print(&self,
+                 out: &mut io::Write,
+                 name: &str,
+                 indent: usize) -> io::Result<()> {
+        try!(writeln!(out, "{:indent$}{}: {}",
+                      "", name, self.label, indent = indent));
+
+        if self.is_array() {
+            for (row, values) in self.children.chunks(ARRAY_ROW_LEN)
+                .enumerate() {
+                let values: Vec<&str> =
+                    values.iter().map(|&(_, ref v)| &v.label[..]).collect();
+
+                try!(writeln!(out, "{:indent$}{:04x}: {}",
+                              "",
+                              row * ARRAY_ROW_LEN,
+                              values.join(" "),
+                              indent = indent + 2));
+            }
+        } else {
+            for &(ref n, ref c) in &self.children {
+                try!(c.print(out, n, indent + 2));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Difference found between two savestates
+pub struct Difference {
+    /// Path of the field, for instance `cpu.regs[3]`
+    pub path: String,
+    /// Value in the first savestate, `None` if the field is missing
+    pub left: Option<String>,
+    /// Value in the second savestate, `None` if the field is missing
+    pub right: Option<String>,
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let missing = "<missing>";
+
+        write!(f, "{}: {} -> {}",
+               self.path,
+               self.left.as_ref().map(|s| &s[..]).unwrap_or(missing),
+               self.right.as_ref().map(|s| &s[..]).unwrap_or(missing))
+    }
+}
+
+/// Decode the savestate in `reader` and return its tree. If the
+/// emulator state can't be decoded, for instance because the
+/// savestate was created by an incompatible version, we fall back on
+/// `raw_tree`.
+pub fn load(reader: &mut io::Read) -> Result<Node, Error> {
+    let mut state = Vec::new();
+
+    try!(reader.read_to_end(&mut state).map_err(Error::IoError));
+
+    let mut reader = &state[..];
+    let mut decoder = try!(savestate::Decoder::new(&mut reader));
+
+    let header = decoder.header().clone();
+
+    decoder.set_migrations(::savestate_migrations());
+
+    let (metadata, cpu) =
+        match ::decode_state(&mut decoder, &header) {
+            Ok(d) => d,
+            Err(e) => return raw_tree(&state, e),
+        };
+
+    Ok(Node {
+        label: "Savestate".into(),
+        children: vec![
+            ("header".into(), try!(header.serialize(Serializer))),
+            ("metadata".into(), try!(metadata.serialize(Serializer))),
+            ("cpu".into(), try!(Encoder::tree(&cpu))),
+        ],
+    })
+}
+
+/// Build the tree of a savestate that failed to decode with `error`.
+/// The format isn't self-describing so past the header we can only
+/// split the contents on what looks like symbols and dump the values
+/// between them as bytes.
+fn raw_tree(state: &[u8], error: Error) -> Result<Node, Error> {
+    let mut reader = state;
+    let mut decoder = try!(savestate::Decoder::new(&mut reader));
+
+    let header = decoder.header().clone();
+
+    let contents = try!(decoder.read_to_end());
+
+    Ok(Node {
+        label: "Savestate (undecoded)".into(),
+        children: vec![
+            ("header".into(), try!(header.serialize(Serializer))),
+            ("error".into(), Node::leaf(error)),
+            ("contents".into(), raw_contents(&contents, header.compact)),
+        ],
+    })
+}
+
+/// Split `contents` on the symbols it seems to contain. In the
+/// verbose encoding symbols are identifiers prefixed by their length,
+/// in the compact encoding they're hashes we can't tell apart from
+/// the values so everything is dumped as bytes.
+fn raw_contents(contents: &[u8], compact: bool) -> Node {
+    let mut children = Vec::new();
+    // Number of times each symbol has been seen so far
+    let mut counts = HashMap::new();
+
+    // Name of the symbol preceding `values`
+    let mut name = String::new();
+    let mut values = Vec::new();
+
+    let mut pos = 0;
+
+    while pos < contents.len() {
+        let symbol =
+            if compact {
+                None
+            } else {
+                raw_symbol(&contents[pos..])
+            };
+
+        match symbol {
+            Some(s) => {
+                if !name.is_empty() || !values.is_empty() {
+                    push_raw(&mut children, &mut counts, name, &values);
+                }
+
+                name = s.into();
+                values.clear();
+                pos += 4 + s.len();
+            }
+            None => {
+                values.push(contents[pos]);
+                pos += 1;
+            }
+        }
+    }
+
+    push_raw(&mut children, &mut counts, name, &values);
+
+    Node {
+        label: format!("[{}]", contents.len()),
+        children: children,
+    }
+}
+
+/// Return the symbol at the start of `raw`, if it looks like one
+fn raw_symbol(raw: &[u8]) -> Option<&str> {
+    if raw.len() < 4 {
+        return None;
+    }
+
+    let len = raw[..4].iter().rev().fold(0, |l, &b| (l << 8) | b as usize);
+
+    if len == 0 || len > RAW_SYMBOL_MAX_LEN || raw.len() < 4 + len {
+        return None;
+    }
+
+    let symbol = &raw[4..4 + len];
+
+    let is_ident =
+        !symbol[0].is_ascii_digit() &&
+        symbol.iter().all(|&b| b.is_ascii_alphanumeric() || b == b'_');
+
+    if is_ident {
+        ::std::str::from_utf8(symbol).ok()
+    } else {
+        None
+    }
+}
+
+/// Add the bytes `values` following the symbol `name` to `children`.
+/// Symbols usually appear several times so we number the duplicates
+/// to keep the paths unique for `diff`.
+fn push_raw(children: &mut Vec<(String, Node)>,
+            counts: &mut HashMap<String, usize>,
+            name: String,
+            values: &[u8]) {
+    let name =
+        if name.is_empty() {
+            "[start]".into()
+        } else {
+            name
+        };
+
+    let count = {
+        let count = counts.entry(name.clone()).or_insert(0);
+
+        *count += 1;
+        *count
+    };
+
+    let name =
+        if count == 1 {
+            name
+        } else {
+            format!("{}#{}", name, count)
+        };
+
+    let bytes =
+        values.iter().enumerate()
+        .map(|(i, v)| (format!("[{}]", i), Node::leaf(format!("{:02x}", v))))
+        .collect();
+
+    let node = Node {
+        label: format!("[{}]", values.len()),
+        children: bytes,
+    };
+
+    children.push((name, node));
+}
+
+/// Compare the trees `a` and `b` and return their differences
+pub fn diff(a: &Node, b: &Node) -> Vec<Difference> {
+    let mut differences = Vec::new();
+
+    diff_nodes("", a, b, &mut differences);
+
+    differences
+}
+
+fn diff_nodes(path: &str,
+              a: &Node,
+              b: &Node,
+              differences: &mut Vec<Difference>) {
+    if a.label != b.label {
+        differences.push(Difference {
+            path: path.into(),
+            left: Some(a.label.clone()),
+            right: Some(b.label.clone()),
+        });
+    }
+
+    // Children usually appear in the same order on both sides,
+    // sequence elements always do, so we compare them by position
+    // until the names diverge and only look the rest up by name.
+    let common =
+        a.children.iter().zip(&b.children)
+        .take_while(|&(&(ref na, _), &(ref nb, _))| na == nb)
+        .count();
+
+    for (&(ref name, ref ca), &(_, ref cb)) in
+        a.children.iter().zip(&b.children).take(common) {
+        diff_nodes(&child_path(path, name), ca, cb, differences);
+    }
+
+    let rest_a = &a.children[common..];
+    let rest_b = &b.children[common..];
+
+    let names_a: HashSet<&str> =
+        rest_a.iter().map(|&(ref n, _)| n.as_str()).collect();
+    let by_name_b: HashMap<&str, &Node> =
+        rest_b.iter().map(|&(ref n, ref c)| (n.as_str(), c)).collect();
+
+    for &(ref name, ref ca) in rest_a {
+        let path = child_path(path, name);
+
+        match by_name_b.get(name.as_str()) {
+            Some(cb) => diff_nodes(&path, ca, cb, differences),
+            None => differences.push(Difference {
+                path: path,
+                left: Some(ca.label.clone()),
+                right: None,
+            }),
+        }
+    }
+
+    for &(ref name, ref cb) in rest_b {
+        if !names_a.contains(name.as_str()) {
+            differences.push(Difference {
+                path: child_path(path, name),
+                left: None,
+                right: Some(cb.label.clone()),
+            });
+        }
+    }
+}
+
+fn child_path(path: &str, name: &str) -> String {
+    if path.is_empty() || name.starts_with('[') {
+        format!("{}{}", path, name)
+    } else {
+        format!("{}.{}", path, name)
+    }
+}
+
+/// Serializer building the tree of a value
+struct Serializer;
+
+impl ser::Serializer for Serializer {
+    type Ok = Node;
+    type Error = Error;
+
+    type SerializeSeq = Compound;
+    type SerializeTuple = Compound;
+    type SerializeTupleStruct = Compound;
+    type SerializeTupleVariant = Compound;
+    type SerializeMap = Compound;
+    type SerializeStruct = Compound;
+    type SerializeStructVariant = Compound;
+
+    fn serialize_bool(self, v: bool) -> Result<Node, Error> {
+        Ok(Node::leaf(v))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Node, Error> {
+        Ok(Node::leaf(v))
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Node, Error> {
+        Ok(Node::leaf(v))
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Node, Error> {
+        Ok(Node::leaf(v))
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Node, Error> {
+        Ok(Node::leaf(v))
+    }
+
+    // Unsigned values are mostly registers and memory contents, they
+    // are easier to read in hexadecimal
+
+    fn serialize_u8(self, v: u8) -> Result<Node, Error> {
+        Ok(Node::leaf(format!("{:02x}", v)))
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Node, Error> {
+        Ok(Node::leaf(format!("0x{:04x}", v)))
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Node, Error> {
+        Ok(Node::leaf(format!("0x{:08x}", v)))
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Node, Error> {
+        Ok(Node::leaf(format!("0x{:016x}", v)))
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<Node, Error> {
+        Ok(Node::leaf(v))
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<Node, Error> {
+        Ok(Node::leaf(v))
+    }
+
+    fn serialize_char(self, v: char) -> Result<Node, Error> {
+        Ok(Node::leaf(format!("{:?}", v)))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Node, Error> {
+        Ok(Node::leaf(format!("{:?}", v)))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Node, Error> {
+        let mut seq = Compound::new(None);
+
+        for b in v {
+            try!(ser::SerializeSeq::serialize_element(&mut seq, b));
+        }
+
+        ser::SerializeSeq::end(seq)
+    }
+
+    fn serialize_none(self) -> Result<Node, Error> {
+        Ok(Node::leaf("None"))
+    }
+
+    fn serialize_some<T>(self, v: &T) -> Result<Node, Error>
+        where T: ?Sized + Serialize {
+        v.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Node, Error> {
+        Ok(Node::leaf("()"))
+    }
+
+    fn serialize_unit_struct(self, name: &'static str) -> Result<Node, Error> {
+        Ok(Node::leaf(name))
+    }
+
+    fn serialize_unit_variant(self,
+                              name: &'static str,
+                              _: u32,
+                              variant: &'static str) -> Result<Node, Error> {
+        Ok(Node::leaf(format!("{}::{}", name, variant)))
+    }
+
+    fn serialize_newtype_struct<T>(self,
+                                   name: &'static str,
+                                   v: &T) -> Result<Node, Error>
+        where T: ?Sized + Serialize {
+        Ok(Node {
+            label: name.into(),
+            children: vec![("0".into(), try!(v.serialize(Serializer)))],
+        })
+    }
+
+    fn serialize_newtype_variant<T>(self,
+                                    name: &'static str,
+                                    _: u32,
+                                    variant: &'static str,
+                                    v: &T) -> Result<Node, Error>
+        where T: ?Sized + Serialize {
+        Ok(Node {
+            label: format!("{}::{}", name, variant),
+            children: vec![("0".into(), try!(v.serialize(Serializer)))],
+        })
+    }
+
+    fn serialize_seq(self, _: Option<usize>) -> Result<Compound, Error> {
+        Ok(Compound::new(None))
+    }
+
+    fn serialize_tuple(self, _: usize) -> Result<Compound, Error> {
+        Ok(Compound::new(None))
+    }
+
+    fn serialize_tuple_struct(self,
+                              name: &'static str,
+                              _: usize) -> Result<Compound, Error> {
+        Ok(Compound::new(Some(name.into())))
+    }
+
+    fn serialize_tuple_variant(self,
+                               name: &'static str,
+                               _: u32,
+                               variant: &'static str,
+                               _: usize) -> Result<Compound, Error> {
+        Ok(Compound::new(Some(format!("{}::{}", name, variant))))
+    }
+
+    fn serialize_map(self, _: Option<usize>) -> Result<Compound, Error> {
+        Ok(Compound::new(None))
+    }
+
+    fn serialize_struct(self,
+                        name: &'static str,
+                        _: usize) -> Result<Compound, Error> {
+        Ok(Compound::new(Some(name.into())))
+    }
+
+    fn serialize_struct_variant(self,
+                                name: &'static str,
+                                _: u32,
+                                variant: &'static str,
+                                _: usize) -> Result<Compound, Error> {
+        Ok(Compound::new(Some(format!("{}::{}", name, variant))))
+    }
+}
+
+/// Builder for the node of a compound type
+struct Compound {
+    /// Label of the node, `None` for sequences whose label is their
+    /// length
+    label: Option<String>,
+    children: Vec<(String, Node)>,
+    /// Name of the next map value
+    key: Option<String>,
+}
+
+impl Compound {
+    fn new(label: Option<String>) -> Compound {
+        Compound {
+            label: label,
+            children: Vec::new(),
+            key: None,
+        }
+    }
+
+    fn push<T>(&mut self, name: String, v: &T) -> Result<(), Error>
+        where T: ?Sized + Serialize {
+
+        let node = try!(v.serialize(Serializer));
+
+        self.children.push((name, node));
+
+        Ok(())
+    }
+
+    /// Push the next element of a sequence or tuple
+    fn push_element<T>(&mut self, v: &T) -> Result<(), Error>
+        where T: ?Sized + Serialize {
+
+        let name = format!("[{}]", self.children.len());
+
+        self.push(name, v)
+    }
+
+    fn end(self) -> Result<Node, Error> {
+        let label =
+            match self.label {
+                Some(l) => l,
+                None => format!("[{}]", self.children.len()),
+            };
+
+        Ok(Node {
+            label: label,
+            children: self.children,
+        })
+    }
+}
+
+impl ser::SerializeSeq for Compound {
+    type Ok = Node;
+    type Error = Error;
+
+    fn serialize_element<T>(&mut self, v: &T) -> Result<(), Error>
+        where T: ?Sized + Serialize {
+        self.push_element(v)
+    }
+
+    fn end(self) -> Result<Node, Error> {
+        Compound::end(self)
+    }
+}
+
+impl ser::SerializeTuple for Compound {
+    type Ok = Node;
+    type Error = Error;
+
+    fn serialize_element<T>(&mut self, v: &T) -> Result<(), Error>
+        where T: ?Sized + Serialize {
+        self.push_element(v)
+    }
+
+    fn end(self) -> Result<Node, Error> {
+        Compound::end(self)
+    }
+}
+
+impl ser::SerializeTupleStruct for Compound {
+    type Ok = Node;
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self, v: &T) -> Result<(), Error>
+        where T: ?Sized + Serialize {
+        self.push_element(v)
+    }
+
+    fn end(self) -> Result<Node, Error> {
+        Compound::end(self)
+    }
+}
+
+impl ser::SerializeTupleVariant for Compound {
+    type Ok = Node;
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self, v: &T) -> Result<(), Error>
+        where T: ?Sized + Serialize {
+        self.push_element(v)
+    }
+
+    fn end(self) -> Result<Node, Error> {
+        Compound::end(self)
+    }
+}
+
+impl ser::SerializeMap for Compound {
+    type Ok = Node;
+    type Error = Error;
+
+    fn serialize_key<T>(&mut self, k: &T) -> Result<(), Error>
+        where T: ?Sized + Serialize {
+        let key = try!(k.serialize(Serializer));
+
+        self.key = Some(format!("[{}]", key.label));
+
+        Ok(())
+    }
+
+    fn serialize_value<T>(&mut self, v: &T) -> Result<(), Error>
+        where T: ?Sized + Serialize {
+        let name = self.key.take().unwrap_or_else(|| "[?]".into());
+
+        self.push(name, v)
+    }
+
+    fn end(self) -> Result<Node, Error> {
+        Compound::end(self)
+    }
+}
+
+impl ser::SerializeStruct for Compound {
+    type Ok = Node;
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self,
+                          name: &'static str,
+                          v: &T) -> Result<(), Error>
+        where T: ?Sized + Serialize {
+        self.push(name.into(), v)
+    }
+
+    fn end(self) -> Result<Node, Error> {
+        Compound::end(self)
+    }
+}
+
+impl ser::SerializeStructVariant for Compound {
+    type Ok = Node;
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self,
+                          name: &'static str,
+                          v: &T) -> Result<(), Error>
+        where T: ?Sized + Serialize {
+        self.push(name.into(), v)
+    }
+
+    fn end(self) -> Result<Node, Error> {
+        Compound::end(self)
+    }
+}
+
+/// `rustc_serialize::Encoder` building the tree of a value. The
+/// emulator state only implements `rustc-serialize`'s traits, its
+/// nodes are labeled like the ones built by `Serializer`.
+struct Encoder {
+    /// Label of the node, `None` for sequences whose label is their
+    /// length
+    label: Option<String>,
+    children: Vec<(String, Node)>,
+    /// Name of the enum being encoded
+    enum_name: Option<String>,
+    /// Name of the next map value
+    key: Option<String>,
+}
+
+impl Encoder {
+    fn new() -> Encoder {
+        Encoder {
+            label: None,
+            children: Vec::new(),
+            enum_name: None,
+            key: None,
+        }
+    }
+
+    /// Build the tree of `v`
+    fn tree<T: ?Sized + Encodable>(v: &T) -> Result<Node, Error> {
+        Encoder::build(|e| v.encode(e))
+    }
+
+    /// Build the node of the value encoded by `f`
+    fn build<F>(f: F) -> Result<Node, Error>
+        where F: FnOnce(&mut Encoder) -> Result<(), Error> {
+
+        let mut encoder = Encoder::new();
+
+        try!(f(&mut encoder));
+
+        let label =
+            match encoder.label {
+                Some(l) => l,
+                None => format!("[{}]", encoder.children.len()),
+            };
+
+        Ok(Node {
+            label: label,
+            children: encoder.children,
+        })
+    }
+
+    fn leaf<T: ToString>(&mut self, v: T) -> Result<(), Error> {
+        self.label = Some(v.to_string());
+
+        Ok(())
+    }
+
+    fn push<F>(&mut self, name: String, f: F) -> Result<(), Error>
+        where F: FnOnce(&mut Encoder) -> Result<(), Error> {
+
+        let node = try!(Encoder::build(f));
+
+        self.children.push((name, node));
+
+        Ok(())
+    }
+
+    /// Push the next element of a sequence or tuple
+    fn push_element<F>(&mut self, f: F) -> Result<(), Error>
+        where F: FnOnce(&mut Encoder) -> Result<(), Error> {
+
+        let name = format!("[{}]", self.children.len());
+
+        self.push(name, f)
+    }
+}
+
+impl ::rustc_serialize::Encoder for Encoder {
+    type Error = Error;
+
+    fn emit_nil(&mut self) -> Result<(), Error> {
+        self.leaf("()")
+    }
+
+    fn emit_usize(&mut self, v: usize) -> Result<(), Error> {
+        self.leaf(v)
+    }
+
+    // Unsigned values are mostly registers and memory contents, they
+    // are easier to read in hexadecimal
+
+    fn emit_u64(&mut self, v: u64) -> Result<(), Error> {
+        self.leaf(format!("0x{:016x}", v))
+    }
+
+    fn emit_u32(&mut self, v: u32) -> Result<(), Error> {
+        self.leaf(format!("0x{:08x}", v))
+    }
+
+    fn emit_u16(&mut self, v: u16) -> Result<(), Error> {
+        self.leaf(format!("0x{:04x}", v))
+    }
+
+    fn emit_u8(&mut self, v: u8) -> Result<(), Error> {
+        self.leaf(format!("{:02x}", v))
+    }
+
+    fn emit_isize(&mut self, v: isize) -> Result<(), Error> {
+        self.leaf(v)
+    }
+
+    fn emit_i64(&mut self, v: i64) -> Result<(), Error> {
+        self.leaf(v)
+    }
+
+    fn emit_i32(&mut self, v: i32) -> Result<(), Error> {
+        self.leaf(v)
+    }
+
+    fn emit_i16(&mut self, v: i16) -> Result<(), Error> {
+        self.leaf(v)
+    }
+
+    fn emit_i8(&mut self, v: i8) -> Result<(), Error> {
+        self.leaf(v)
+    }
+
+    fn emit_bool(&mut self, v: bool) -> Result<(), Error> {
+        self.leaf(v)
+    }
+
+    fn emit_f64(&mut self, v: f64) -> Result<(), Error> {
+        self.leaf(v)
+    }
+
+    fn emit_f32(&mut self, v: f32) -> Result<(), Error> {
+        self.leaf(v)
+    }
+
+    fn emit_char(&mut self, v: char) -> Result<(), Error> {
+        self.leaf(format!("{:?}", v))
+    }
+
+    fn emit_str(&mut self, v: &str) -> Result<(), Error> {
+        self.leaf(format!("{:?}", v))
+    }
+
+    fn emit_enum<F>(&mut self, name: &str, f: F) -> Result<(), Error>
+        where F: FnOnce(&mut Self) -> Result<(), Error> {
+
+        self.enum_name = Some(name.into());
+
+        f(self)
+    }
+
+    fn emit_enum_variant<F>(&mut self,
+                            v_name: &str,
+                            _v_id: usize,
+                            _len: usize,
+                            f: F) -> Result<(), Error>
+        where F: FnOnce(&mut Self) -> Result<(), Error> {
+
+        let label =
+            match self.enum_name {
+                Some(ref e) => format!("{}::{}", e, v_name),
+                None => v_name.into(),
+            };
+
+        self.label = Some(label);
+
+        f(self)
+    }
+
+    fn emit_enum_variant_arg<F>(&mut self,
+                                _a_idx: usize,
+                                f: F) -> Result<(), Error>
+        where F: FnOnce(&mut Self) -> Result<(), Error> {
+
+        self.push_element(f)
+    }
+
+    fn emit_enum_struct_variant<F>(&mut self,
+                                   v_name: &str,
+                                   v_id: usize,
+                                   len: usize,
+                                   f: F) -> Result<(), Error>
+        where F: FnOnce(&mut Self) -> Result<(), Error> {
+
+        self.emit_enum_variant(v_name, v_id, len, f)
+    }
+
+    fn emit_enum_struct_variant_field<F>(&mut self,
+                                         f_name: &str,
+                                         _f_idx: usize,
+                                         f: F) -> Result<(), Error>
+        where F: FnOnce(&mut Self) -> Result<(), Error> {
+
+        self.push(f_name.into(), f)
+    }
+
+    fn emit_struct<F>(&mut self,
+                      name: &str,
+                      _: usize,
+                      f: F) -> Result<(), Error>
+        where F: FnOnce(&mut Self) -> Result<(), Error> {
+
+        self.label = Some(name.into());
+
+        f(self)
+    }
+
+    fn emit_struct_field<F>(&mut self,
+                            f_name: &str,
+                            _: usize,
+                            f: F) -> Result<(), Error>
+        where F: FnOnce(&mut Self) -> Result<(), Error> {
+
+        self.push(f_name.into(), f)
+    }
+
+    fn emit_tuple<F>(&mut self, _: usize, f: F) -> Result<(), Error>
+        where F: FnOnce(&mut Self) -> Result<(), Error> {
+
+        f(self)
+    }
+
+    fn emit_tuple_arg<F>(&mut self, _: usize, f: F) -> Result<(), Error>
+        where F: FnOnce(&mut Self) -> Result<(), Error> {
+
+        self.push_element(f)
+    }
+
+    fn emit_tuple_struct<F>(&mut self,
+                            name: &str,
+                            _: usize,
+                            f: F) -> Result<(), Error>
+        where F: FnOnce(&mut Self) -> Result<(), Error> {
+
+        self.label = Some(name.into());
+
+        f(self)
+    }
+
+    fn emit_tuple_struct_arg<F>(&mut self,
+                                _: usize,
+                                f: F) -> Result<(), Error>
+        where F: FnOnce(&mut Self) -> Result<(), Error> {
+
+        self.push_element(f)
+    }
+
+    fn emit_option<F>(&mut self, f: F) -> Result<(), Error>
+        where F: FnOnce(&mut Self) -> Result<(), Error> {
+
+        f(self)
+    }
+
+    fn emit_option_none(&mut self) -> Result<(), Error> {
+        self.leaf("None")
+    }
+
+    fn emit_option_some<F>(&mut self, f: F) -> Result<(), Error>
+        where F: FnOnce(&mut Self) -> Result<(), Error> {
+
+        f(self)
+    }
+
+    fn emit_seq<F>(&mut self, _: usize, f: F) -> Result<(), Error>
+        where F: FnOnce(&mut Self) -> Result<(), Error> {
+
+        f(self)
+    }
+
+    fn emit_seq_elt<F>(&mut self, _: usize, f: F) -> Result<(), Error>
+        where F: FnOnce(&mut Self) -> Result<(), Error> {
+
+        self.push_element(f)
+    }
+
+    fn emit_map<F>(&mut self, _: usize, f: F) -> Result<(), Error>
+        where F: FnOnce(&mut Self) -> Result<(), Error> {
+
+        f(self)
+    }
+
+    fn emit_map_elt_key<F>(&mut self, _idx: usize, f: F) -> Result<(), Error>
+        where F: FnOnce(&mut Self) -> Result<(), Error> {
+
+        let key = try!(Encoder::build(f));
+
+        self.key = Some(format!("[{}]", key.label));
+
+        Ok(())
+    }
+
+    fn emit_map_elt_val<F>(&mut self, _idx: usize, f: F) -> Result<(), Error>
+        where F: FnOnce(&mut Self) -> Result<(), Error> {
+
+        let name = self.key.take().unwrap_or_else(|| "[?]".into());
+
+        self.push(name, f)
+    }
+}
+
+/// Number of values per row when printing arrays
+const ARRAY_ROW_LEN: usize = 16;
+/// Longest symbol recognized by `raw_symbol`
+const RAW_SYMBOL_MAX_LEN: usize = 64;
+
+#[test]
+fn test_diff() {
+    #[derive(Serialize, RustcEncodable)]
+    struct Regs {
+        pc: u32,
+        flags: Vec<bool>,
+        ram: Vec<u8>,
+    }
+
+    let a = Regs { pc: 0x100, flags: vec![true, false], ram: vec![0; 40] };
+    let b = Regs { pc: 0x104, flags: vec![true], ram: vec![0; 40] };
+
+    // The emulator state goes through `rustc_serialize::Encoder`
+    assert_eq!(Encoder::tree(&a).unwrap(), a.serialize(Serializer).unwrap());
+
+    let a = a.serialize(Serializer).unwrap();
+    let mut b = b.serialize(Serializer).unwrap();
+
+    assert!(diff(&a, &a).is_empty());
+
+    let differences: Vec<String> =
+        diff(&a, &b).iter().map(|d| d.to_string()).collect();
+
+    assert_eq!(differences,
+               ["pc: 0x00000100 -> 0x00000104",
+                "flags: [2] -> [1]",
+                "flags[1]: false -> <missing>"]);
+
+    b.children[0].1 = Node::leaf("0x00000100");
+    b.children[1] = a.children[1].clone();
+    b.children[2].1.children[33].1 = Node::leaf("ff");
+
+    let differences: Vec<String> =
+        diff(&a, &b).iter().map(|d| d.to_string()).collect();
+
+    assert_eq!(differences, ["ram[33]: 00 -> ff"]);
+
+    // Fields are matched by name when they're out of order
+    b.children.swap(0, 2);
+
+    let differences: Vec<String> =
+        diff(&a, &b).iter().map(|d| d.to_string()).collect();
+
+    assert_eq!(differences, ["ram[33]: 00 -> ff"]);
+
+    let mut out = Vec::new();
+
+    a.print(&mut out, "regs", 0).unwrap();
+
+    let out = String::from_utf8(out).unwrap();
+
+    assert!(out.starts_with("regs: Regs\n  pc: 0x00000100\n"));
+    assert!(out.contains("  ram: [40]\n    0000: 00 00"));
+    assert!(out.contains("    0020: 00 00 00 00 00 00 00 00\n"));
+}
+
+#[test]
+fn test_load_undecodable() {
+    #[derive(Serialize)]
+    struct Bogus {
+        counter: u32,
+        data: Vec<u8>,
+    }
+
+    let bogus = vec![Bogus { counter: 0x2a, data: vec![1, 2] },
+                     Bogus { counter: 7, data: Vec::new() }];
+
+    let mut state = Vec::new();
+
+    {
+        let header = savestate::Header::new("test", "");
+        let mut e = savestate::Encoder::new(&mut state, &header).unwrap();
+
+        bogus.serialize(&mut e).unwrap();
+    }
+
+    let tree = load(&mut &state[..]).unwrap();
+
+    assert_eq!(tree.label, "Savestate (undecoded)");
+    assert_eq!(tree.children[0].0, "header");
+
+    let contents = &tree.children[2].1;
+
+    let names: Vec<&str> =
+        contents.children.iter().map(|&(ref n, _)| &n[..]).collect();
+
+    assert_eq!(names, ["[start]", "Bogus", "counter", "data",
+                       "Bogus#2", "counter#2", "data#2"]);
+
+    let counter: Vec<&str> =
+        contents.children[2].1.children.iter()
+        .map(|&(_, ref v)| &v.label[..]).collect();
+
+    assert_eq!(counter, ["2a", "00", "00", "00"]);
+    assert_eq!(contents.children[3].1.label, "[6]");
+}
@@ -1,15 +1,26 @@
 #[macro_use]
 pub mod libretro;
 mod retrolog;
-mod savestate;
+pub mod savestate;
 mod debugger;
+mod cheats;
+mod memcard;
+mod bios;
+mod hle;
+mod rewind;
+mod display;
+pub mod inspect;
+pub mod metadata;
 
 use std::path::{Path, PathBuf};
-use std::fs::{File, metadata};
-use std::io::Read;
+use std::mem;
+use std::ffi::CString;
+use std::sync::Arc;
+use std::sync::Mutex;
 
 use libc::c_char;
 
+use serde::{Serialize, Deserialize};
 use rustc_serialize::{Encodable, Decodable};
 
 use pockystation::{MASTER_CLOCK_HZ};
@@ -19,10 +30,19 @@ use pockystation::dac;
 use pockystation::dac::Dac;
 use pockystation::rtc::Bcd;
 use pockystation::memory::{Interconnect, Byte};
-use pockystation::memory::bios::{Bios, BIOS_SIZE};
-use pockystation::memory::flash::{Flash, FLASH_SIZE};
+use pockystation::memory::bios::Bios;
+use pockystation::memory::flash::Flash;
 
 use debugger::Debugger;
+use cheats::Cheats;
+use memcard::BLOCK_SIZE;
+use memcard::format::CardFile;
+use memcard::directory::Directory;
+use hle::Hle;
+use rewind::Rewind;
+use metadata::Metadata;
+use display::{Palette, Renderer, Combine, Layout, Buttons, Rotation};
+use sha1_smol::Sha1;
 
 #[macro_use]
 extern crate log;
@@ -30,28 +50,26 @@ extern crate libc;
 extern crate pockystation;
 extern crate time;
 extern crate rustc_serialize;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate encoding_rs;
+extern crate sha1_smol;
+extern crate crc32fast;
+extern crate miniz_oxide;
 
 /// Static system information sent to the frontend on request
 const SYSTEM_INFO: libretro::SystemInfo = libretro::SystemInfo {
     library_name: cstring!("Pockystation"),
     library_version: pockystation::VERSION_CSTR as *const _ as *const c_char,
-    valid_extensions: cstring!("mcr"),
+    valid_extensions: cstring!("mcr|mcd|srm|gme|vmp|psv|mcs"),
     need_fullpath: false,
     block_extract: false,
 };
 
-const SYSTEM_AV_INFO: libretro::SystemAvInfo = libretro::SystemAvInfo {
-    geometry: libretro::GameGeometry {
-        base_width: 32,
-        base_height: 32,
-        max_width: 32,
-        max_height: 32,
-        aspect_ratio: 1./1.,
-    },
-    timing: libretro::SystemTiming {
-        fps: 60.,
-        sample_rate: dac::SAMPLE_RATE_HZ as f64,
-    }
+const SYSTEM_TIMING: libretro::SystemTiming = libretro::SystemTiming {
+    fps: 60.,
+    sample_rate: dac::SAMPLE_RATE_HZ as f64,
 };
 
 struct Context {
@@ -62,9 +80,21 @@ struct Context {
     /// If true the emulated RTC is periodically synchronized with the
     /// host clock.
     rtc_host_sync: bool,
-    /// If true the emulator will rotate the display when the software
-    /// requests it
-    lcd_rotation_en: bool,
+    /// Rotation of the display, `None` to rotate it by 180 degrees
+    /// when the software requests it
+    lcd_rotation: Option<Rotation>,
+    /// Colors used to render the LCD
+    palette: Palette,
+    /// LCD response model used to render the frames
+    renderer: Renderer,
+    /// Number of slices each frame is split into, the LCD is sampled
+    /// after each one
+    lcd_samples: u32,
+    /// Layout of the video output
+    layout: Layout,
+    /// State of the buttons during the last frame, displayed on the
+    /// shell
+    buttons: Buttons,
     /// Countdown for RTC synchronization with host if
     /// `rtc_host_sync` is true. Decreases by one every frame,
     /// synchronizes when it reaches 0.
@@ -73,6 +103,57 @@ struct Context {
     savestate_max_len: usize,
     /// If true we trigger the debugger when Pause/Break is pressed
     debug_on_key: bool,
+    /// Memory card file the flash was loaded from
+    card_file: CardFile,
+    /// Copy of the flash contents as they were last read from or
+    /// written to `card_file`. Used to detect modifications.
+    flash_saved: Vec<u8>,
+    /// SHA-1 of the directory block of `card_file` as it was loaded,
+    /// stored in savestates to identify the card
+    card_hash: String,
+    /// If true the flash is written back to `card_file` when it's
+    /// been modified
+    flash_writeback: bool,
+    /// Number of frames between two checks for modified flash
+    /// contents. If 0 the flash is only written back on unload.
+    flash_writeback_delay: u32,
+    /// Countdown for the periodic flash writeback. Decreases by one
+    /// every frame, writes back the flash when it reaches 0.
+    flash_writeback_counter: u32,
+    /// Cheats set by the frontend, applied every frame. They're not
+    /// affected by resets or savestates.
+    cheats: Cheats,
+    /// Directory filename of the application selected with the
+    /// `boot_app` option, `None` to boot the first one
+    boot_app: Option<String>,
+    /// Path of the BIOS in use, `None` if we use the HLE BIOS
+    bios_path: Option<PathBuf>,
+    /// Copy of the BIOS in use, attached to the emulator state when a
+    /// savestate is loaded since the BIOS isn't stored in savestates
+    bios: Bios,
+    /// SHA-1 of the BIOS in use, stored in savestates
+    bios_hash: String,
+    /// HLE BIOS state if no BIOS dump was found
+    hle: Option<Hle>,
+    /// If true the flash contents stored in savestates are restored
+    /// when they're loaded
+    savestate_flash: bool,
+    /// If true savestates are compressed
+    savestate_compress: bool,
+    /// If true savestates use the compact encoding, storing symbols
+    /// as tags. The verbose encoding is easier to debug.
+    savestate_compact: bool,
+    /// Rewind buffer, snapshots are captured every
+    /// `REWIND_INTERVAL_FRAMES`
+    rewind: Rewind,
+    /// Countdown for the next rewind snapshot. Decreases by one every
+    /// frame, captures a snapshot when it reaches 0.
+    rewind_counter: u32,
+    /// Number of frames emulated ahead of the input, 0 if run-ahead
+    /// is disabled
+    run_ahead: u32,
+    /// Audio output, shared with the `AudioBackend` of the DAC
+    audio: Arc<Mutex<AudioOutput>>,
 }
 
 impl Context {
@@ -84,24 +165,67 @@ impl Context {
             return Err(());
         }
 
-        let cpu = try!(Context::load(flash));
+        let audio = Arc::new(Mutex::new(AudioOutput::new()));
+
+        let (cpu, card_file, bios, bios_path, bios_hash) =
+            try!(Context::load(flash, &audio));
+
+        let flash_saved = cpu.interconnect().flash().data().to_vec();
+        let card_hash = Sha1::from(&flash_saved[..BLOCK_SIZE]).digest();
 
         let mut context = Context {
             cpu: cpu,
             debugger: Debugger::new(),
-            lcd_rotation_en: true,
+            lcd_rotation: None,
+            palette: display::WHITE_ON_BLACK,
+            renderer: Renderer::new(),
+            lcd_samples: 1,
+            layout: Layout {
+                scale: 1,
+                gaps: false,
+                shell: false,
+                rotation: Rotation::Deg0,
+            },
+            buttons: Buttons::default(),
             rtc_host_sync: false,
             rtc_sync_counter: 0,
             savestate_max_len: 0,
             debug_on_key: false,
+            card_file: card_file,
+            flash_saved: flash_saved,
+            card_hash: card_hash.to_string(),
+            flash_writeback: false,
+            flash_writeback_delay: 0,
+            flash_writeback_counter: 0,
+            cheats: Cheats::new(),
+            boot_app: None,
+            hle: None,
+            bios_path: bios_path,
+            bios: bios,
+            bios_hash: bios_hash,
+            savestate_flash: false,
+            savestate_compress: false,
+            savestate_compact: false,
+            rewind: Rewind::new(),
+            rewind_counter: 0,
+            run_ahead: 0,
+            audio: audio,
         };
 
+        if context.bios_path.is_none() {
+            context.hle = Some(Hle::new());
+        }
+
         libretro::Context::refresh_variables(&mut context);
 
+        context.select_boot_app();
+
         let max_len = try!(context.compute_savestate_max_length());
 
         context.savestate_max_len = max_len;
 
+        context.set_memory_maps();
+
         if CoreVariables::debug_on_reset() {
             context.trigger_break();
         }
@@ -109,9 +233,10 @@ impl Context {
         Ok(context)
     }
 
-    fn load(memory_card: &Path) -> Result<Cpu, ()> {
+    fn load(memory_card: &Path, audio: &Arc<Mutex<AudioOutput>>)
+            -> Result<(Cpu, CardFile, Bios, Option<PathBuf>, String), ()> {
 
-        let flash =
+        let (card_file, flash) =
             match Context::load_flash(memory_card) {
                 Some(f) => f,
                 None => {
@@ -120,148 +245,215 @@ impl Context {
                 }
             };
 
-        let bios =
-            match Context::find_bios() {
-                Some(c) => c,
+        let bios_candidates = Context::bios_candidates();
+
+        // Some of the option values depend on the card and system
+        // directory contents, we have to register them before
+        // loading the BIOS
+        let boot_app_desc = boot_app_option(flash.data());
+        let bios_desc = bios_option(&bios_candidates);
+
+        CoreVariables::register_with(&[("boot_app", &boot_app_desc),
+                                       ("bios", &bios_desc)]);
+
+        let bios_file_name = CoreVariables::bios();
+
+        let (bios, bios_path, bios_hash) =
+            match bios::select(&bios_candidates,
+                               bios_file_name.as_ref().map(|n| &n[..])) {
+                Some(c) => {
+                    info!("Using BIOS {:?}", c.path);
+                    // Can't fail, the candidates have already been
+                    // checked
+                    (Bios::new(&c.data).unwrap(),
+                     Some(c.path.clone()),
+                     c.sha1.clone())
+                }
                 None => {
-                    error!("Couldn't find a BIOS, bailing out");
-                    return Err(())
+                    warn!("Couldn't find a BIOS, using the HLE BIOS. \
+                           Compatibility will be limited.");
+
+                    let rom = hle::rom();
+                    let hash = Sha1::from(&rom).digest().to_string();
+
+                    match Bios::new(&rom) {
+                        Some(b) => (b, None, hash),
+                        None => {
+                            error!("The HLE BIOS image was rejected, \
+                                    bailing out");
+                            return Err(())
+                        }
+                    }
                 }
             };
 
-        let dac = Dac::new(Box::new(AudioBackend::new()));
-
-        let inter = Interconnect::new(bios, flash, dac);
+        // Only the HLE BIOS can boot an application directly, don't
+        // offer a choice the real BIOS would ignore
+        if bios_path.is_some() {
+            let boot_app_desc =
+                CString::new("Boot application (HLE BIOS only); auto")
+                .unwrap();
 
-        Ok(Cpu::new(inter))
-    }
+            CoreVariables::register_with(&[("boot_app", &boot_app_desc),
+                                           ("bios", &bios_desc)]);
+        }
 
-    fn load_flash(path: &Path) -> Option<Flash> {
-        match metadata(path) {
-            Ok(md) => {
-                if md.len() == FLASH_SIZE as u64 {
-                    let mut file =
-                        match File::open(path) {
-                            Ok(f) => f,
-                            Err(e) => {
-                                warn!("Can't open {:?}: {}", path, e);
-                                return None;
-                            }
-                        };
+        let dac = Dac::new(Box::new(AudioBackend::new(audio.clone())));
 
-                    // Load the flash
-                    let mut data = vec![0; FLASH_SIZE as usize];
+        let inter = Interconnect::new(bios.clone(), flash, dac);
 
-                    if let Err(e) = file.read_exact(&mut data) {
-                        warn!("Error while reading {:?}: {}", path, e);
-                        return None;
-                    }
+        Ok((Cpu::new(inter), card_file, bios, bios_path, bios_hash))
+    }
 
-                    match Flash::new(&data) {
-                        Some(flash) => {
-                            info!("Loaded flash memory from {:?}", path);
-                            Some(flash)
-                        }
-                        None => {
-                            debug!("Failed to load {:?}", path);
-                            None
-                        }
-                    }
-                } else {
-                    error!("Invalid flash memory length (expected {}, got {})",
-                           FLASH_SIZE, md.len());
-                    None
+    fn load_flash(path: &Path) -> Option<(CardFile, Flash)> {
+        let (card_file, data) =
+            match CardFile::load(path) {
+                Ok(c) => c,
+                Err(e) => {
+                    error!("Can't load memory card {:?}: {:?}", path, e);
+                    return None;
                 }
+            };
+
+        match Flash::new(&data) {
+            Some(flash) => {
+                info!("Loaded flash memory from {:?} ({:?})",
+                      path, card_file.format());
+                Context::log_directory(&data);
+                Some((card_file, flash))
             }
-            Err(e) => {
-                error!("Can't get file size for {:?}: {}", path, e);
+            None => {
+                debug!("Failed to load {:?}", path);
                 None
             }
         }
     }
 
-    /// Attempt to find the PocketStation BIOS in the system
-    /// directory
-    fn find_bios() -> Option<Bios> {
-        let system_directory =
-            match libretro::get_system_directory() {
-                Some(dir) => dir,
-                None => {
-                    error!("The frontend didn't give us a system directory, \
-                            no BIOS can be loaded");
-                    return None;
-                }
-            };
-
+    /// Log the contents of the memory card and any inconsistency
+    /// found in its directory
+    fn log_directory(flash: &[u8]) {
         let dir =
-            match ::std::fs::read_dir(&system_directory) {
+            match Directory::parse(flash) {
                 Ok(d) => d,
                 Err(e) => {
-                    error!("Can't read directory {:?}: {}",
-                           system_directory, e);
-                    return None;
+                    warn!("Can't parse memory card directory: {:?}", e);
+                    return;
                 }
             };
 
-        for entry in dir {
-            match entry {
-                Ok(entry) => {
-                    let path = entry.path();
-
-                    match entry.metadata() {
-                        Ok(md) => {
-                            if !md.is_file() {
-                                debug!("Ignoring {:?}: not a file", path);
-                            } else if md.len() != BIOS_SIZE as u64 {
-                                debug!("Ignoring {:?}: bad size", path);
-                            } else {
-                                let bios = Context::try_bios(&path);
-
-                                if bios.is_some() {
-                                    // Found a valid BIOS!
-                                    return bios;
-                                }
-                            }
-                        }
-                        Err(e) =>
-                            warn!("Ignoring {:?}: can't get file metadata: {}",
-                                  path, e)
-                    }
-                }
-                Err(e) => warn!("Error while reading directory: {}", e),
-            }
+        for save in &dir.saves {
+            let kind =
+                match save.executable {
+                    Some(e) => format!("{:?}", e),
+                    None => "data".into(),
+                };
+
+            info!("Block {:>2}: {} \"{}\" ({} blocks, {})",
+                  save.first_block(),
+                  save.filename,
+                  save.title,
+                  save.blocks.len(),
+                  kind);
+        }
+
+        for problem in &dir.problems {
+            warn!("Memory card directory problem: {:?}", problem);
         }
 
-        None
+        if dir.executables().next().is_none() {
+            warn!("The memory card doesn't contain any PocketStation \
+                   application");
+        }
     }
 
-    /// Attempt to read and load the BIOS at `path`
-    fn try_bios(path: &Path) -> Option<Bios> {
-        let mut file =
-            match File::open(&path) {
-                Ok(f) => f,
-                Err(e) => {
-                    warn!("Can't open {:?}: {}", path, e);
-                    return None;
-                }
-            };
+    /// Pass the application selected with the `boot_app` option to
+    /// the HLE BIOS which boots it directly. The real BIOS menu starts
+    /// on the first application and we don't know where it stores its
+    /// selection, reordering the card instead would mess with the
+    /// user's saves. With a real BIOS the option is registered with
+    /// "auto" as its only value, we only get a selection here if the
+    /// frontend kept a stale one.
+    fn select_boot_app(&mut self) {
+        match self.hle {
+            Some(ref mut hle) => hle.set_boot_app(self.boot_app.clone()),
+            None =>
+                if let Some(ref app) = self.boot_app {
+                    warn!("Can't boot {} directly with a real BIOS", app);
+                    libretro_message!(MESSAGE_FRAMES,
+                                      "The boot application can only be \
+                                       selected with the HLE BIOS");
+                },
+        }
+    }
 
-        // Load the BIOS
-        let mut data = vec![0; BIOS_SIZE as usize];
+    /// Describe the PocketStation address map to the frontend. Must be
+    /// called again every time the emulated memory is reallocated
+    /// (when a savestate is loaded for instance).
+    fn set_memory_maps(&mut self) {
+        let inter = self.cpu.interconnect_mut();
+
+        let descriptors = [
+            memory_descriptor(libretro::memdesc::SYSTEM_RAM,
+                              &mut inter.ram_mut().data_mut()[..],
+                              RAM_BASE),
+            memory_descriptor(libretro::memdesc::SAVE_RAM,
+                              &mut inter.flash_mut().data_mut()[..],
+                              FLASH_BASE),
+        ];
+
+        if !libretro::set_memory_maps(&descriptors) {
+            warn!("The frontend doesn't support memory maps");
+        }
+    }
+
+    /// Write the flash back to the memory card file if it's been
+    /// modified since it was last loaded or saved
+    fn save_flash(&mut self) {
+        let data = self.cpu.interconnect().flash().data();
 
-        if let Err(e) = file.read_exact(&mut data) {
-            warn!("Error while reading {:?}: {}", path, e);
-            return None;
+        if data[..] == self.flash_saved[..] {
+            // Nothing changed
+            return;
         }
 
-        match Bios::new(&data) {
-            Some(bios) => {
-                info!("Using BIOS {:?}", path);
-                Some(bios)
+        match self.card_file.save(&data[..]) {
+            Ok(_) => {
+                info!("Saved flash memory to {:?}", self.card_file.path());
+                self.flash_saved = data.to_vec();
             }
+            Err(memcard::format::Error::SignedContainer(format)) => {
+                error!("Refusing to write back the flash to {:?}: the {:?} \
+                        signature can't be updated",
+                       self.card_file.path(), format);
+                libretro_message!(MESSAGE_FRAMES,
+                                  "Memory card writeback disabled: PSP and \
+                                   PS3 saves are signed");
+                // Don't try again every time the flash is modified
+                self.flash_writeback = false;
+            }
+            Err(memcard::format::Error::SingleSaveMoved) => {
+                error!("Refusing to write back the flash to {:?}: the save \
+                        doesn't fit in the original file anymore",
+                       self.card_file.path());
+                libretro_message!(MESSAGE_FRAMES,
+                                  "Memory card writeback disabled: the save \
+                                   was moved or resized");
+                self.flash_writeback = false;
+            }
+            Err(e) =>
+                error!("Couldn't save flash memory to {:?}: {:?}",
+                       self.card_file.path(), e),
+        }
+    }
+
+    /// Return the BIOS candidates found in the system directory
+    fn bios_candidates() -> Vec<bios::Candidate> {
+        match libretro::get_system_directory() {
+            Some(dir) => bios::candidates(&dir),
             None => {
-                debug!("Ignoring {:?}: not a known PocketStation BIOS", path);
-                None
+                error!("The frontend didn't give us a system directory, \
+                        no BIOS can be loaded");
+                Vec::new()
             }
         }
     }
@@ -288,9 +480,18 @@ impl Context {
 
         let mut counter = WriteCounter(0);
 
-        try!(self.save_state(&mut counter));
+        // The savestate options can be changed at any time so we have
+        // to use the biggest format: verbose and uncompressed
+        let mut header = self.savestate_header();
+
+        header.compact = false;
+        header.compressed = false;
+
+        try!(self.save_state(&mut counter, &header));
 
-        let len = counter.0;
+        // Compression can also be enabled and might make the
+        // savestate slightly bigger if the data is incompressible
+        let len = savestate::max_len(counter.0);
 
         // Our savestate format has variable length so let's add a bit of headroom
         let len = len + 1024;
@@ -298,10 +499,12 @@ impl Context {
         Ok(len)
     }
 
-    fn save_state(&self, writer: &mut ::std::io::Write) -> Result<(), ()> {
+    fn save_state(&self,
+                  writer: &mut ::std::io::Write,
+                  header: &savestate::Header) -> Result<(), ()> {
 
         let mut encoder =
-            match savestate::Encoder::new(writer) {
+            match savestate::Encoder::new(writer, header) {
                 Ok(encoder) => encoder,
                 Err(e) => {
                     warn!("Couldn't create savestate encoder: {:?}", e);
@@ -309,48 +512,153 @@ impl Context {
                 }
             };
 
-        match self.cpu.encode(&mut encoder) {
+        let boot_app = self.boot_app.as_ref().map(|s| &s[..]);
+        let metadata = Metadata::capture(&self.cpu, boot_app);
+
+        if let Err(e) = metadata.serialize(&mut encoder) {
+            warn!("Couldn't serialize savestate metadata: {:?}", e);
+            return Err(());
+        }
+
+        if let Err(e) = self.cpu.encode(&mut encoder) {
+            warn!("Couldn't serialize emulator state: {:?}", e);
+            return Err(());
+        }
+
+        match encoder.finish() {
             Ok(_) => Ok(()),
             Err(e) => {
-                warn!("Couldn't serialize emulator state: {:?}", e);
+                warn!("Couldn't write savestate: {:?}", e);
                 Err(())
             }
         }
     }
 
-    fn load_state(&mut self, reader: &mut ::std::io::Read) -> Result<(), ()> {
+    fn savestate_header(&self) -> savestate::Header {
+        let mut header =
+            savestate::Header::new(pockystation::VERSION, &self.bios_hash);
+
+        header.card_hash = self.card_hash.clone();
+        header.compressed = self.savestate_compress;
+        header.compact = self.savestate_compact;
+
+        header
+    }
+
+    /// Load a savestate. If `restore_flash` is true the flash is
+    /// restored as well, otherwise we keep the current contents.
+    fn load_state(&mut self,
+                  reader: &mut ::std::io::Read,
+                  restore_flash: bool) -> Result<(), ()> {
         let mut decoder =
             match savestate::Decoder::new(reader) {
                 Ok(decoder) => decoder,
                 Err(e) => {
                     warn!("Couldn't create savestate decoder: {:?}", e);
+
+                    match e {
+                        savestate::Error::BadMagic =>
+                            libretro_message!(MESSAGE_FRAMES,
+                                              "Can't load savestate: \
+                                               not a PockyStation savestate"),
+                        savestate::Error::UnsupportedVersion(v) =>
+                            libretro_message!(MESSAGE_FRAMES,
+                                              "Can't load savestate: format \
+                                               version {} is too recent, \
+                                               please update the core", v),
+                        _ =>
+                            libretro_message!(MESSAGE_FRAMES,
+                                              "Can't load savestate: \
+                                               invalid header"),
+                    }
+
                     return Err(())
                 }
             };
 
-        let mut cpu: Cpu =
-            match Decodable::decode(&mut decoder) {
-                Ok(cpu) => cpu,
+        let header = decoder.header().clone();
+
+        debug!("Loading savestate v{} created by PockyStation {}",
+               header.version, header.core_version);
+
+        if header.core_version != pockystation::VERSION {
+            warn!("Savestate created by PockyStation {}, we're running {}",
+                  header.core_version, pockystation::VERSION);
+        }
+
+        // The BIOS isn't stored in savestates, the state wouldn't be
+        // consistent with another one. Legacy savestates don't record
+        // the BIOS so we have to trust them.
+        if header.version > 0 && header.bios_hash != self.bios_hash {
+            error!("Savestate created with BIOS {}, we're using {}",
+                   header.bios_hash, self.bios_hash);
+            libretro_message!(MESSAGE_FRAMES,
+                              "Can't load savestate: it was created with \
+                               another BIOS");
+            return Err(())
+        }
+
+        // Restoring the flash replaces the contents of the card with
+        // the ones of the card the savestate was created with. Older
+        // savestates don't record the card.
+        if restore_flash &&
+            header.version >= 7 &&
+            header.card_hash != self.card_hash {
+            warn!("Savestate created with memory card {}, we're using {}",
+                  header.card_hash, self.card_hash);
+            libretro_message!(MESSAGE_FRAMES,
+                              "Savestate created with another memory card, \
+                               restoring its flash contents");
+        }
+
+        decoder.set_migrations(savestate_migrations());
+
+        let (_, mut cpu) =
+            match decode_state(&mut decoder, &header) {
+                Ok(d) => d,
                 Err(e) => {
                     warn!("Couldn't decode savestate: {:?}", e);
+                    libretro_message!(MESSAGE_FRAMES,
+                                      "Can't load savestate created by \
+                                       PockyStation {}: incompatible with \
+                                       version {}",
+                                      header.core_version,
+                                      pockystation::VERSION);
                     return Err(())
                 }
             };
 
-        let bios =
-            match Context::find_bios() {
-                Some(c) => c,
-                None => {
-                    error!("Couldn't find a BIOS, bailing out");
-                    return Err(())
-                }
-            };
+        cpu.interconnect_mut().set_bios(self.bios.clone());
 
-        let flash = self.cpu.interconnect().flash().data().clone();
+        // The new backend writes to the same output, the samples
+        // buffered so far aren't lost
+        let backend = AudioBackend::new(self.audio.clone());
+
+        cpu.interconnect_mut().dac_mut().set_backend(Box::new(backend));
+
+        // The frontend may hold pointers to the RAM and flash returned
+        // by `memory_data` or the memory maps, so we copy the decoded
+        // RAM and flash into the current buffers and hand the current
+        // buffers over to the new `Cpu` instead of letting them move.
+        {
+            let ram = self.cpu.interconnect_mut().ram_mut().data_mut();
+
+            ram.copy_from_slice(&cpu.interconnect().ram().data()[..]);
+
+            mem::swap(ram, cpu.interconnect_mut().ram_mut().data_mut());
+        }
+
+        {
+            let flash = self.cpu.interconnect_mut().flash_mut().data_mut();
+
+            if restore_flash {
+                flash.copy_from_slice(&cpu.interconnect().flash().data()[..]);
+            } else {
+                debug!("Ignoring the flash contents of the savestate");
+            }
 
-        cpu.interconnect_mut().set_bios(bios);
-        cpu.interconnect_mut().flash_mut().set_data(flash);
-        cpu.interconnect_mut().dac_mut().set_backend(Box::new(AudioBackend::new()));
+            mem::swap(flash, cpu.interconnect_mut().flash_mut().data_mut());
+        }
 
         self.cpu = cpu;
 
@@ -369,6 +677,15 @@ impl Context {
                 };
 
             irq_controller.set_raw_interrupt(irq, active);
+
+            match irq {
+                Interrupt::ActionButton => self.buttons.action = active,
+                Interrupt::UpButton => self.buttons.up = active,
+                Interrupt::DownButton => self.buttons.down = active,
+                Interrupt::LeftButton => self.buttons.left = active,
+                Interrupt::RightButton => self.buttons.right = active,
+                _ => (),
+            }
         }
     }
 
@@ -414,6 +731,197 @@ impl Context {
         }
     }
 
+    /// Capture a rewind snapshot. They always include the flash since
+    /// the emulated software can modify it.
+    fn rewind_capture(&mut self) {
+        let mut header = self.savestate_header();
+
+        // Deltas are smaller with the uncompressed, compact encoding
+        header.compressed = false;
+        header.compact = true;
+
+        let mut snapshot = Vec::new();
+
+        match self.save_state(&mut snapshot, &header) {
+            Ok(_) => self.rewind.push(snapshot),
+            Err(_) => warn!("Couldn't capture rewind snapshot"),
+        }
+    }
+
+    /// Go back to the previous rewind snapshot, if any
+    fn rewind_step(&mut self) {
+        let snapshot =
+            match self.rewind.pop() {
+                Some(s) => s.to_vec(),
+                // We reached the end of the buffer
+                None => return,
+            };
+
+        if self.load_state(&mut &snapshot[..], true).is_err() {
+            error!("Couldn't load rewind snapshot, dropping the buffer");
+            self.rewind.clear();
+        }
+    }
+
+    /// Emulate a single frame. Speculative frames are thrown away
+    /// once displayed (run-ahead) so they can't trigger the debugger.
+    /// They still apply the cheats, otherwise the displayed frame
+    /// wouldn't match what the player gets once it's emulated for
+    /// real.
+    fn run_frame(&mut self, speculative: bool) {
+        self.cheats.apply(&mut self.cpu);
+
+        // Only keep the samples of the frame we're about to display
+        self.renderer.clear_samples();
+
+        let slices = self.lcd_samples;
+
+        // Step for 1/60th of a second, sampling the LCD after each
+        // slice
+        let ticks = MASTER_CLOCK_HZ / 60;
+
+        for i in 0..slices {
+            let mut slice_ticks = ticks / slices;
+
+            if i == slices - 1 {
+                slice_ticks += ticks % slices;
+            }
+
+            let debugger =
+                if speculative {
+                    None
+                } else {
+                    Some(&mut self.debugger)
+                };
+
+            let mut hooks = hle::Hooks {
+                debugger: debugger,
+                hle: self.hle.as_mut(),
+            };
+
+            self.cpu.run_ticks(&mut hooks, slice_ticks);
+
+            self.renderer.sample(&lcd_rows(&self.cpu));
+        }
+    }
+
+    /// Run `run_ahead` frames with the current input and display the
+    /// last one, then restore the state as it was before. This way
+    /// the effects of the input show up `run_ahead` frames early.
+    fn run_ahead_frames(&mut self) {
+        let mut header = self.savestate_header();
+
+        header.compressed = false;
+        header.compact = true;
+
+        let mut snapshot = Vec::new();
+
+        // The speculative frames can write to the flash so we have to
+        // restore it as well
+        if self.save_state(&mut snapshot, &header).is_err() {
+            warn!("Couldn't snapshot the state for run-ahead");
+            self.output_frame();
+            return;
+        }
+
+        // The speculative frames could update the HLE BIOS state that
+        // lives outside of the emulator state (the warnings already
+        // emitted), we restore it along with the snapshot
+        let hle = self.hle.clone();
+
+        self.audio.lock().unwrap().muted = true;
+
+        for _ in 0..self.run_ahead {
+            self.run_frame(true);
+        }
+
+        self.audio.lock().unwrap().muted = false;
+
+        self.output_frame();
+
+        if self.load_state(&mut &snapshot[..], true).is_err() {
+            error!("Couldn't restore the state after run-ahead, \
+                    disabling it");
+            self.run_ahead = 0;
+        }
+
+        self.hle = hle;
+    }
+
+    /// Send the current contents of the LCD to the frontend
+    fn output_frame(&mut self) {
+        // We haven't run the emulator since the last frame (rewind),
+        // sample the LCD as it is now
+        if !self.renderer.has_samples() {
+            self.renderer.sample(&lcd_rows(&self.cpu));
+        }
+
+        let rotation =
+            match self.lcd_rotation {
+                Some(r) => r,
+                None =>
+                    if self.cpu.interconnect().lcd().rotated() {
+                        Rotation::Deg180
+                    } else {
+                        Rotation::Deg0
+                    },
+            };
+
+        if rotation != self.layout.rotation {
+            let layout = Layout {
+                rotation: rotation,
+                ..self.layout
+            };
+
+            self.set_layout(layout);
+        }
+
+        let lcd = self.renderer.render(&self.palette);
+
+        let fb_out = self.layout.draw(&lcd, &self.palette, &self.buttons);
+
+        let (width, height) = self.layout.size();
+
+        libretro::frame_done(&fb_out, width, height);
+    }
+
+    /// Change the layout of the video output, notifying the frontend
+    /// if the geometry changes
+    fn set_layout(&mut self, layout: Layout) {
+        let resized = layout.size() != self.layout.size();
+
+        self.layout = layout;
+
+        if resized && !libretro::set_geometry(&self.geometry()) {
+            warn!("Couldn't update the output geometry");
+        }
+    }
+
+    /// Describe the current video output to the frontend
+    fn geometry(&self) -> libretro::GameGeometry {
+        let (width, height) = self.layout.size();
+
+        // The largest output the user can select, it can be rotated
+        // so the maximum must be square
+        let max = Layout {
+            scale: MAX_SCALE,
+            gaps: false,
+            shell: true,
+            rotation: Rotation::Deg0,
+        };
+
+        let (max_width, max_height) = max.size();
+        let max_size = max_width.max(max_height);
+
+        libretro::GameGeometry {
+            base_width: width,
+            base_height: height,
+            max_width: max_size,
+            max_height: max_size,
+            aspect_ratio: width as f32 / height as f32,
+        }
+    }
+
     /// Trigger a breakpoint in the debugger
     fn trigger_break(&mut self) {
         pockystation::debugger::Debugger::trigger_break(&mut self.debugger);
@@ -423,6 +931,18 @@ impl Context {
 impl libretro::Context for Context {
 
     fn render_frame(&mut self) {
+        let rewinding =
+            self.rewind.depth() > 0 &&
+            libretro::button_pressed(0, REWIND_BUTTON);
+
+        if rewinding {
+            self.rewind_step();
+            // Capture again as soon as the button is released
+            self.rewind_counter = 0;
+            self.output_frame();
+            return;
+        }
+
         self.poll_controllers();
 
         let debug_request =
@@ -442,49 +962,84 @@ impl libretro::Context for Context {
             self.rtc_sync_counter -= 1;
         }
 
-        // Step for 1/60th of a second
-        self.cpu.run_ticks(&mut self.debugger, MASTER_CLOCK_HZ / 60);
-
-        let lcd = self.cpu.interconnect().lcd();
-
-        let fb = lcd.framebuffer();
-
-        let mut fb_out = [0u32; 32 * 32];
-
-        let rotate = self.lcd_rotation_en && lcd.rotated();
-
-        for y in 0..32 {
-            let row = fb[y];
+        if self.flash_writeback && self.flash_writeback_delay > 0 {
+            if self.flash_writeback_counter == 0 {
+                self.save_flash();
+                self.flash_writeback_counter = self.flash_writeback_delay;
+            }
 
-            for x in 0..32 {
-                if ((row >> x) & 1) == 0 {
-                    let mut off = y * 32 + x;
+            self.flash_writeback_counter -= 1;
+        }
 
-                    if rotate {
-                        off = 32 * 32 - off - 1;
-                    }
+        self.run_frame(false);
 
-                    fb_out[off] = 0xffffff;
-                }
+        if self.rewind.depth() > 0 {
+            if self.rewind_counter == 0 {
+                self.rewind_capture();
+                self.rewind_counter = REWIND_INTERVAL_FRAMES;
             }
+
+            self.rewind_counter -= 1;
         }
 
-        libretro::frame_done(fb_out);
+        if self.run_ahead > 0 {
+            self.run_ahead_frames();
+        } else {
+            self.output_frame();
+        }
     }
 
     fn get_system_av_info(&self) -> libretro::SystemAvInfo {
-        SYSTEM_AV_INFO
+        libretro::SystemAvInfo {
+            geometry: self.geometry(),
+            timing: SYSTEM_TIMING,
+        }
     }
 
     fn refresh_variables(&mut self) {
         self.rtc_host_sync = CoreVariables::rtc_host_sync();
-        self.lcd_rotation_en = CoreVariables::lcd_rotation_en();
+        self.lcd_rotation = CoreVariables::lcd_rotation();
+        self.palette =
+            match CoreVariables::lcd_palette() {
+                Some(p) => p,
+                None => Palette {
+                    on: CoreVariables::lcd_color_on(),
+                    off: CoreVariables::lcd_color_off(),
+                },
+            };
+        self.renderer.set_decay(CoreVariables::lcd_ghosting());
+        self.renderer.set_combine(CoreVariables::lcd_sample_mode());
+        self.lcd_samples = CoreVariables::lcd_samples();
+
+        // The rotation is updated when the next frame is rendered
+        let layout = Layout {
+            scale: CoreVariables::lcd_scale(),
+            gaps: CoreVariables::lcd_pixel_gaps(),
+            shell: CoreVariables::device_shell(),
+            rotation: self.layout.rotation,
+        };
+
+        self.set_layout(layout);
+
         self.debug_on_key = CoreVariables::debug_on_key();
+        self.flash_writeback = CoreVariables::flash_writeback();
+        self.flash_writeback_delay = CoreVariables::flash_writeback_delay();
+        self.boot_app = CoreVariables::boot_app();
+        self.savestate_flash = CoreVariables::savestate_flash();
+        self.savestate_compress = CoreVariables::savestate_compress();
+        self.savestate_compact = CoreVariables::savestate_compact();
+        self.rewind.set_depth(CoreVariables::rewind_depth());
+        self.run_ahead = CoreVariables::run_ahead();
+
+        if self.flash_writeback_counter > self.flash_writeback_delay {
+            self.flash_writeback_counter = self.flash_writeback_delay;
+        }
 
         self.cpu.set_debug_on_bkpt(CoreVariables::debug_on_bkpt());
     }
 
     fn reset(&mut self) {
+        self.select_boot_app();
         self.cpu.reset();
 
         if CoreVariables::debug_on_reset() {
@@ -503,14 +1058,186 @@ impl libretro::Context for Context {
     }
 
     fn serialize(&self, mut buf: &mut [u8]) -> Result<(), ()> {
-        self.save_state(&mut buf)
+        self.save_state(&mut buf, &self.savestate_header())
     }
 
     fn unserialize(&mut self, mut buf: &[u8]) -> Result<(), ()> {
-        self.load_state(&mut buf)
+        self.load_state(&mut buf, self.savestate_flash)
+    }
+
+    fn memory_data(&mut self,
+                   mem: libretro::MemoryType) -> Option<&mut [u8]> {
+        match mem {
+            libretro::MemoryType::SaveRam => {
+                let flash = self.cpu.interconnect_mut().flash_mut();
+
+                Some(&mut flash.data_mut()[..])
+            }
+            libretro::MemoryType::SystemRam => {
+                let ram = self.cpu.interconnect_mut().ram_mut();
+
+                Some(&mut ram.data_mut()[..])
+            }
+            _ => None,
+        }
+    }
+
+    fn cheat_reset(&mut self) {
+        self.cheats.reset();
+    }
+
+    fn cheat_set(&mut self, index: u32, enabled: bool, code: &str) {
+        let ram_len = self.cpu.interconnect().ram().data().len();
+
+        if let Err(e) = self.cheats.set(index as usize,
+                                        enabled,
+                                        code,
+                                        ram_len) {
+            warn!("Invalid cheat #{} {:?}: {:?}", index, code, e);
+        }
     }
 }
 
+impl Drop for Context {
+    fn drop(&mut self) {
+        if self.flash_writeback {
+            self.save_flash();
+        }
+    }
+}
+
+/// Build the memory descriptor for a region of the PocketStation
+/// address map starting at `base`. Peripherals are decoded from the
+/// top byte of the address, the region is mirrored throughout its
+/// 16MB window.
+fn memory_descriptor(flags: u64,
+                     mem: &mut [u8],
+                     base: u32) -> libretro::MemoryDescriptor {
+    libretro::MemoryDescriptor {
+        flags: flags,
+        ptr: mem.as_mut_ptr() as *mut _,
+        offset: 0,
+        start: base as libc::size_t,
+        select: 0xff000000,
+        disconnect: 0,
+        len: mem.len() as libc::size_t,
+        addrspace: ::std::ptr::null(),
+    }
+}
+
+/// Build the description of the `boot_app` option listing the
+/// applications found on the card. The values start with the
+/// directory filename, which identifies the application even if the
+/// card is reorganized.
+fn boot_app_option(flash: &[u8]) -> CString {
+    let mut desc = String::from("Boot application (applied on reset); auto");
+
+    if let Ok(dir) = Directory::parse(flash) {
+        for save in dir.executables() {
+            // '|' separates the option values, we can't list these
+            if save.filename.is_empty() || save.filename.contains('|') {
+                continue;
+            }
+
+            let title: String =
+                save.title.trim().chars()
+                .filter(|&c| c != '\0')
+                .map(|c| if c == '|' { '/' } else { c })
+                .collect();
+
+            desc.push('|');
+            desc.push_str(&save.filename);
+
+            if !title.is_empty() {
+                desc.push_str(": ");
+                desc.push_str(&title);
+            }
+        }
+    }
+
+    CString::new(desc).unwrap()
+}
+
+/// Build the description of the `bios` option listing the BIOS
+/// candidates found in the system directory
+fn bios_option(candidates: &[bios::Candidate]) -> CString {
+    let mut desc = String::from("BIOS (restart); auto");
+
+    for c in candidates {
+        if c.verdict == bios::Verdict::Rejected {
+            continue;
+        }
+
+        match c.file_name() {
+            // '|' separates the option values, we can't list these
+            Some(name) if !name.contains('|') => {
+                desc.push('|');
+                desc.push_str(name);
+            }
+            _ => debug!("Can't list {:?} in the BIOS option", c.path),
+        }
+    }
+
+    CString::new(desc).unwrap()
+}
+
+/// Migrations used to load savestates created before fields were
+/// added to or removed from the emulator state. When a field is added
+/// a default value must be registered with `default_field`, when one
+/// is removed it must be registered with `skip_field`, for instance:
+///
+/// ```ignore
+/// migrations.default_field("Timer", "prescaler", &0u8).unwrap();
+/// migrations.skip_field::<u32>("Lcd", "old_field");
+/// ```
+fn savestate_migrations() -> savestate::Migrations {
+    savestate::Migrations::new()
+}
+
+/// Return the current contents of the LCD, one `u32` per row. Bit `x`
+/// of row `y` is the pixel at `(x, y)`, it's 0 if the pixel is on.
+fn lcd_rows(cpu: &Cpu) -> [u32; 32] {
+    let fb = cpu.interconnect().lcd().framebuffer();
+
+    let mut rows = [0; 32];
+
+    for y in 0..32 {
+        rows[y] = fb[y];
+    }
+
+    rows
+}
+
+/// Decode the metadata and emulator state following the savestate
+/// header
+fn decode_state(decoder: &mut savestate::Decoder,
+                header: &savestate::Header)
+                -> Result<(Option<Metadata>, Cpu), savestate::Error> {
+    let metadata = try!(metadata::decode(&mut *decoder, header));
+
+    let cpu = try!(Cpu::decode(&mut *decoder));
+
+    // Versions 2 to 5 stored an optional second copy of the flash
+    // after the emulator state, it's identical to the one in the
+    // `Cpu`
+    if header.version >= 2 && header.version < 6 {
+        try!(Option::<LegacySavedFlash>::deserialize(&mut *decoder));
+    }
+
+    Ok((metadata, cpu))
+}
+
+/// Copy of the flash stored by savestate versions 2 to 5, ignored
+#[derive(Deserialize)]
+#[serde(rename = "SavedFlash")]
+#[allow(dead_code)]
+struct LegacySavedFlash {
+    /// File name of the memory card the flash was loaded from
+    card: String,
+    /// Contents of the flash
+    data: Vec<u8>,
+}
+
 /// Init function, guaranteed called only once (unlike `retro_init`)
 fn init() {
     retrolog::init();
@@ -528,14 +1255,61 @@ libretro_variables!(
     struct CoreVariables (prefix = "pockystation") {
         rtc_host_sync: bool, parse_bool
             => "Synchronize real-time clock with host; disabled|enabled",
-        lcd_rotation_en: bool, parse_bool
-            => "Display rotation; enabled|disabled",
+        lcd_rotation: Option<Rotation>, parse_rotation
+            => "Display rotation; auto|0 degrees|90 degrees|180 degrees|\
+                270 degrees",
+        lcd_palette: Option<Palette>, parse_palette
+            => "LCD colors; white on black|original|high contrast|inverted|\
+                custom",
+        lcd_color_on: u32, parse_color
+            => "Custom LCD color for pixels on; ffffff|000000|1e2619|\
+                9ca68c|808080|ffb000|33ff33|00c0ff|ff4040",
+        lcd_color_off: u32, parse_color
+            => "Custom LCD color for pixels off; 000000|ffffff|9ca68c|\
+                1e2619|808080|302000|003000|001830|300000",
+        lcd_ghosting: f32, parse_ghosting
+            => "LCD ghosting; disabled|light|medium|heavy",
+        lcd_scale: u32, parse_scale
+            => "Output scale; 1x|2x|3x|4x|6x|8x",
+        lcd_pixel_gaps: bool, parse_bool
+            => "LCD pixel gaps (3x scale and above); disabled|enabled",
+        device_shell: bool, parse_bool
+            => "Draw the PocketStation shell; disabled|enabled",
+        lcd_samples: u32, parse_lcd_samples
+            => "LCD samples per frame; 1|2|4|8|16",
+        lcd_sample_mode: Combine, parse_sample_mode
+            => "LCD samples combination; average|last write wins",
         debug_on_bkpt: bool, parse_bool
             => "Trigger debugger on BKPT instructions; disabled|enabled",
         debug_on_key: bool, parse_bool
             => "Trigger debugger when Pause/Break is pressed; disabled|enabled",
         debug_on_reset: bool, parse_bool
             => "Trigger debugger on start or reset; disabled|enabled",
+        flash_writeback: bool, parse_bool
+            => "Write flash back to the memory card file; disabled|enabled",
+        flash_writeback_delay: u32, parse_writeback_delay
+            => "Memory card writeback interval; \
+                on unload only|1 second|5 seconds|30 seconds|1 minute",
+        // The values are filled from the card's directory once it's
+        // loaded
+        boot_app: Option<String>, parse_boot_app
+            => "Boot application (applied on reset); auto",
+        // The values are filled from the system directory's contents
+        // once the game is loaded
+        bios: Option<String>, parse_bios
+            => "BIOS (restart); auto",
+        savestate_flash: bool, parse_bool
+            => "Restore flash from savestates; disabled|enabled",
+        savestate_compress: bool, parse_bool
+            => "Compress savestates; disabled|enabled",
+        savestate_compact: bool, parse_bool
+            => "Compact savestates (harder to debug); disabled|enabled",
+        rewind_depth: usize, parse_rewind_depth
+            => "Rewind (hold L); disabled|10 seconds|30 seconds|1 minute|\
+                5 minutes|30 minutes",
+        run_ahead: u32, parse_run_ahead
+            => "Run-ahead (reduces input latency); \
+                disabled|1 frame|2 frames|3 frames|4 frames",
     });
 
 fn parse_bool(opt: &str) -> Result<bool, ()> {
@@ -546,29 +1320,185 @@ fn parse_bool(opt: &str) -> Result<bool, ()> {
     }
 }
 
+/// Parse the display rotation option, returns `None` for the automatic
+/// rotation
+fn parse_rotation(opt: &str) -> Result<Option<Rotation>, ()> {
+    let rotation =
+        match opt {
+            "auto" => return Ok(None),
+            "0 degrees" => Rotation::Deg0,
+            "90 degrees" => Rotation::Deg90,
+            "180 degrees" => Rotation::Deg180,
+            "270 degrees" => Rotation::Deg270,
+            _ => return Err(()),
+        };
+
+    Ok(Some(rotation))
+}
+
+/// Parse the LCD palette option, returns `None` for the custom
+/// palette
+fn parse_palette(opt: &str) -> Result<Option<Palette>, ()> {
+    let palette =
+        match opt {
+            "white on black" => display::WHITE_ON_BLACK,
+            "original" => display::ORIGINAL,
+            "high contrast" => display::HIGH_CONTRAST,
+            "inverted" => display::INVERTED,
+            "custom" => return Ok(None),
+            _ => return Err(()),
+        };
+
+    Ok(Some(palette))
+}
+
+/// Parse the LCD ghosting option and return the fraction of the pixel
+/// intensity kept from one frame to the next
+fn parse_ghosting(opt: &str) -> Result<f32, ()> {
+    match opt {
+        "disabled" => Ok(0.),
+        "light" => Ok(0.25),
+        "medium" => Ok(0.5),
+        "heavy" => Ok(0.75),
+        _ => Err(()),
+    }
+}
+
+fn parse_scale(opt: &str) -> Result<u32, ()> {
+    if !opt.ends_with('x') {
+        return Err(());
+    }
+
+    match opt[..opt.len() - 1].parse() {
+        Ok(s) if s > 0 && s <= MAX_SCALE => Ok(s),
+        _ => Err(()),
+    }
+}
+
+fn parse_lcd_samples(opt: &str) -> Result<u32, ()> {
+    match opt.parse() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(()),
+    }
+}
+
+fn parse_sample_mode(opt: &str) -> Result<Combine, ()> {
+    match opt {
+        "average" => Ok(Combine::Average),
+        "last write wins" => Ok(Combine::LastWriteWins),
+        _ => Err(()),
+    }
+}
+
+/// Parse an RGB color in hexadecimal
+fn parse_color(opt: &str) -> Result<u32, ()> {
+    if opt.len() != 6 {
+        return Err(());
+    }
+
+    u32::from_str_radix(opt, 16).map_err(|_| ())
+}
+
+/// Parse the flash writeback interval and return it as a number of
+/// frames
+fn parse_writeback_delay(opt: &str) -> Result<u32, ()> {
+    let seconds =
+        match opt {
+            "on unload only" => 0,
+            "1 second" => 1,
+            "5 seconds" => 5,
+            "30 seconds" => 30,
+            "1 minute" => 60,
+            _ => return Err(()),
+        };
+
+    Ok(seconds * 60)
+}
+
+/// Parse the rewind option and return the number of snapshots to
+/// keep
+fn parse_rewind_depth(opt: &str) -> Result<usize, ()> {
+    let seconds =
+        match opt {
+            "disabled" => 0,
+            "10 seconds" => 10,
+            "30 seconds" => 30,
+            "1 minute" => 60,
+            "5 minutes" => 5 * 60,
+            "30 minutes" => 30 * 60,
+            _ => return Err(()),
+        };
+
+    Ok(seconds * 60 / REWIND_INTERVAL_FRAMES as usize)
+}
+
+/// Parse the run-ahead option and return the number of frames to run
+/// ahead
+fn parse_run_ahead(opt: &str) -> Result<u32, ()> {
+    if opt == "disabled" {
+        return Ok(0);
+    }
+
+    match opt.split(' ').next().map(|n| n.parse()) {
+        Some(Ok(n)) => Ok(n),
+        _ => Err(()),
+    }
+}
+
+/// Parse the boot application option and return the directory
+/// filename of the selected application
+fn parse_boot_app(opt: &str) -> Result<Option<String>, ()> {
+    if opt == "auto" {
+        return Ok(None);
+    }
+
+    match opt.split(": ").next() {
+        Some(filename) if !filename.is_empty() => Ok(Some(filename.into())),
+        _ => Err(()),
+    }
+}
+
+/// Parse the BIOS option and return the file name of the selected
+/// BIOS
+fn parse_bios(opt: &str) -> Result<Option<String>, ()> {
+    match opt {
+        "auto" => Ok(None),
+        _ => Ok(Some(opt.into())),
+    }
+}
+
 fn init_variables() {
     CoreVariables::register();
 }
 
-struct AudioBackend {
+/// Audio output state. It's kept outside of the `AudioBackend`
+/// because loading a savestate installs a new backend in the DAC,
+/// which happens every frame with run-ahead.
+struct AudioOutput {
     /// Audio buffer. Libretro always assumes stereo so we'll have to
     /// duplicate each sample.
     buffer: [i16; 2048],
     /// Current position of the write pointer in the buffer
     pos: u16,
+    /// Samples are dropped while this is set. Used to silence the
+    /// speculative run-ahead frames.
+    muted: bool,
 }
 
-impl AudioBackend {
-    fn new() -> AudioBackend {
-        AudioBackend {
+impl AudioOutput {
+    fn new() -> AudioOutput {
+        AudioOutput {
             buffer: [0; 2048],
             pos: 0,
+            muted: false,
         }
     }
-}
 
-impl dac::Backend for AudioBackend {
     fn push_sample(&mut self, sample: i16) {
+        if self.muted {
+            return;
+        }
+
         let pos = self.pos as usize;
 
         // Duplicate the sample for "stereo" output
@@ -584,6 +1514,24 @@ impl dac::Backend for AudioBackend {
     }
 }
 
+struct AudioBackend {
+    output: Arc<Mutex<AudioOutput>>,
+}
+
+impl AudioBackend {
+    fn new(output: Arc<Mutex<AudioOutput>>) -> AudioBackend {
+        AudioBackend {
+            output: output,
+        }
+    }
+}
+
+impl dac::Backend for AudioBackend {
+    fn push_sample(&mut self, sample: i16) {
+        self.output.lock().unwrap().push_sample(sample);
+    }
+}
+
 const BUTTON_MAP: [(libretro::JoyPadButton, Interrupt); 5] =
     [(libretro::JoyPadButton::A,     Interrupt::ActionButton),
      (libretro::JoyPadButton::Up,    Interrupt::UpButton),
@@ -591,6 +1539,76 @@ const BUTTON_MAP: [(libretro::JoyPadButton, Interrupt); 5] =
      (libretro::JoyPadButton::Left,  Interrupt::LeftButton),
      (libretro::JoyPadButton::Right, Interrupt::RightButton)];
 
+/// Base address of the RAM in the PocketStation address space
+const RAM_BASE: u32 = 0x00000000;
+
+/// Base address of the physical flash in the PocketStation address
+/// space. The flash is also accessible through the banked "virtual"
+/// window at 0x02000000 but that mapping is set up at runtime by the
+/// software through the F_BANK registers at 0x06000000, so we can't
+/// describe it to the frontend.
+const FLASH_BASE: u32 = 0x08000000;
+
 /// Number of frame elapsing between RTC synchronization (if the
 /// option is enabled).
 const RTC_SYNC_DELAY_FRAMES: u32 = 60;
+
+/// Number of frames the savestate error messages are displayed for
+const MESSAGE_FRAMES: u32 = 180;
+
+/// Number of frames between two rewind snapshots. While rewinding we
+/// go back one snapshot per frame.
+const REWIND_INTERVAL_FRAMES: u32 = 4;
+
+/// Largest output scale
+const MAX_SCALE: u32 = 8;
+
+/// Button held to rewind
+const REWIND_BUTTON: libretro::JoyPadButton = libretro::JoyPadButton::L;
+
+#[test]
+fn test_memory_descriptors() {
+    let mut ram = vec![0; 2 * 1024];
+    let mut flash = vec![0; 128 * 1024];
+
+    let ram_desc =
+        memory_descriptor(libretro::memdesc::SYSTEM_RAM, &mut ram, RAM_BASE);
+    let flash_desc =
+        memory_descriptor(libretro::memdesc::SAVE_RAM, &mut flash, FLASH_BASE);
+
+    let descriptors = [&ram_desc, &flash_desc];
+
+    // psx-spx PocketStation memory map: base address of each region
+    // and the index of the descriptor expected to cover it
+    let map = [
+        // RAM
+        (0x00000000, Some(0)),
+        // Virtual flash, banked at runtime
+        (0x02000000, None),
+        // BIOS ROM
+        (0x04000000, None),
+        // F_xxx flash control registers
+        (0x06000000, None),
+        // Physical flash
+        (0x08000000, Some(1)),
+        // Interrupt controller
+        (0x0a000000, None),
+        // Timers
+        (0x0b000000, None),
+        // RTC
+        (0x0c000000, None),
+        // LCD
+        (0x0d000000, None),
+    ];
+
+    for &(addr, expected) in &map {
+        let found =
+            descriptors.iter()
+            .position(|d| addr & d.select == d.start);
+
+        assert_eq!(found, expected, "address 0x{:08x}", addr);
+    }
+
+    assert_eq!(ram_desc.len, 2 * 1024);
+    assert_eq!(flash_desc.len, 128 * 1024);
+}
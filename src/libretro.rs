@@ -37,6 +37,13 @@ pub trait Context {
     fn serialize(&self, &mut [u8]) -> Result<(), ()>;
     /// Deserialize the savestate from the provided buffer
     fn unserialize(&mut self, &[u8]) -> Result<(), ()>;
+    /// Return the memory region of type `MemoryType` if the core
+    /// exposes it to the frontend
+    fn memory_data(&mut self, MemoryType) -> Option<&mut [u8]>;
+    /// Remove all cheats
+    fn cheat_reset(&mut self);
+    /// Set the cheat at `index` to `code`, enable or disable it
+    fn cheat_set(&mut self, index: u32, enabled: bool, code: &str);
 }
 
 /// Global context instance holding our emulator state. Libretro
@@ -137,6 +144,36 @@ pub struct Message {
     pub frames: c_uint,
 }
 
+#[repr(C)]
+pub struct MemoryDescriptor {
+    pub flags: u64,
+    pub ptr: *mut c_void,
+    pub offset: size_t,
+    pub start: size_t,
+    pub select: size_t,
+    pub disconnect: size_t,
+    pub len: size_t,
+    pub addrspace: *const c_char,
+}
+
+/// RETRO_MEMDESC_* flags for `MemoryDescriptor`
+pub mod memdesc {
+    pub const CONST: u64 = 1 << 0;
+    pub const BIGENDIAN: u64 = 1 << 1;
+    pub const SYSTEM_RAM: u64 = 1 << 2;
+    pub const SAVE_RAM: u64 = 1 << 3;
+    pub const VIDEO_RAM: u64 = 1 << 4;
+    pub const ALIGN_2: u64 = 1 << 16;
+    pub const ALIGN_4: u64 = 2 << 16;
+    pub const ALIGN_8: u64 = 3 << 16;
+}
+
+#[repr(C)]
+pub struct MemoryMap {
+    pub descriptors: *const MemoryDescriptor,
+    pub num_descriptors: c_uint,
+}
+
 #[derive(Clone, Copy, PartialEq, Eq)]
 pub enum Environment {
     SetMessage = 6,
@@ -148,6 +185,7 @@ pub enum Environment {
     GetVariableUpdate = 17,
     GetLogInterface = 27,
     SetSystemAvInfo = 32,
+    SetMemoryMaps = 36 | 0x10000,
     SetGeometry = 37,
 }
 
@@ -324,6 +362,30 @@ pub enum JoyPadButton {
     R3 = 15,
 }
 
+/// RETRO_MEMORY_* constants
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum MemoryType {
+    SaveRam = 0,
+    Rtc = 1,
+    SystemRam = 2,
+    VideoRam = 3,
+}
+
+impl MemoryType {
+    fn from_id(id: c_uint) -> Option<MemoryType> {
+        let t =
+            match id {
+                0 => MemoryType::SaveRam,
+                1 => MemoryType::Rtc,
+                2 => MemoryType::SystemRam,
+                3 => MemoryType::VideoRam,
+                _ => return None,
+            };
+
+        Some(t)
+    }
+}
+
 #[derive(Clone, Copy, PartialEq, Eq)]
 pub enum PixelFormat {
     Xrgb1555 = 0,
@@ -522,11 +584,17 @@ static mut ENVIRONMENT: EnvironmentFn = dummy::environment;
 // Higher level helper functions
 //*******************************
 
-pub fn frame_done(frame: [u32; 32 * 32]) {
+/// Send a XRGB8888 frame to the frontend
+pub fn frame_done(frame: &[u32], width: u32, height: u32) {
+    assert!(frame.len() >= (width * height) as usize);
+
     unsafe {
         let data = frame.as_ptr() as *const c_void;
 
-        VIDEO_REFRESH(data, 32, 32, 32 * 4);
+        VIDEO_REFRESH(data,
+                      width as c_uint,
+                      height as c_uint,
+                      (width * 4) as size_t);
     }
 }
 
@@ -613,6 +681,21 @@ pub unsafe fn set_system_av_info(av_info: &SystemAvInfo) -> bool {
     call_environment(Environment::SetSystemAvInfo, av_info)
 }
 
+/// Describe the emulated system's address space to the frontend
+/// (used by cheats and achievements). The frontend makes its own copy
+/// of `descriptors` but the memory they point to must remain valid
+/// until the next call.
+pub fn set_memory_maps(descriptors: &[MemoryDescriptor]) -> bool {
+    let map = MemoryMap {
+        descriptors: descriptors.as_ptr(),
+        num_descriptors: descriptors.len() as c_uint,
+    };
+
+    unsafe {
+        call_environment(Environment::SetMemoryMaps, &map)
+    }
+}
+
 /// Display `msg` on the screen for `nframes` frames
 pub fn set_message(nframes: u32, msg: &str) {
     let msg = CString::new(msg);
@@ -829,12 +912,25 @@ pub extern "C" fn retro_unserialize(data: *const c_void,
 
 #[no_mangle]
 pub extern "C" fn retro_cheat_reset() {
+    context().cheat_reset();
 }
 
 #[no_mangle]
-pub fn retro_cheat_set(_index: c_uint,
-                       _enabled: bool,
-                       _code: *const c_char) {
+pub extern "C" fn retro_cheat_set(index: c_uint,
+                                  enabled: bool,
+                                  code: *const c_char) {
+    if code.is_null() {
+        warn!("Got NULL cheat code for index {}", index);
+        return;
+    }
+
+    let code = unsafe { CStr::from_ptr(code) };
+
+    match code.to_str() {
+        Ok(code) => context().cheat_set(index as u32, enabled, code),
+        Err(_) => warn!("Got invalid cheat code for index {}: {}",
+                        index, code.to_string_lossy()),
+    }
 }
 
 #[no_mangle]
@@ -885,14 +981,25 @@ pub extern "C" fn retro_get_region() -> c_uint {
     0
 }
 
+/// Return the memory region `id` exposed by the core, if any
+fn memory_region(id: c_uint) -> Option<&'static mut [u8]> {
+    MemoryType::from_id(id).and_then(|t| context().memory_data(t))
+}
+
 #[no_mangle]
-pub extern "C" fn retro_get_memory_data(_id: c_uint) -> *mut c_void {
-    ptr::null_mut()
+pub extern "C" fn retro_get_memory_data(id: c_uint) -> *mut c_void {
+    match memory_region(id) {
+        Some(m) => m.as_mut_ptr() as *mut c_void,
+        None => ptr::null_mut(),
+    }
 }
 
 #[no_mangle]
-pub extern "C" fn retro_get_memory_size(_id: c_uint) -> size_t {
-    0
+pub extern "C" fn retro_get_memory_size(id: c_uint) -> size_t {
+    match memory_region(id) {
+        Some(m) => m.len() as size_t,
+        None => 0,
+    }
 }
 
 pub mod dummy {
@@ -967,6 +1074,21 @@ pub mod dummy {
         fn unserialize(&mut self, _: &[u8]) -> Result<(), ()> {
             panic!("Called unserialize with no context!");
         }
+
+        fn memory_data(&mut self, _: super::MemoryType) -> Option<&mut [u8]> {
+            // The frontend is allowed to query the memory before a
+            // game is loaded
+            None
+        }
+
+        // Frontends can set cheats before a game is loaded, there's
+        // nothing to apply them to
+
+        fn cheat_reset(&mut self) {
+        }
+
+        fn cheat_set(&mut self, _: u32, _: bool, _: &str) {
+        }
     }
 }
 
@@ -1063,6 +1185,13 @@ macro_rules! cstring {
 /// MyVariables::register();
 /// ```
 ///
+/// If some values are only known at runtime the description of
+/// these variables can be replaced when registering again:
+///
+/// ```rust
+/// MyVariables::register_with(&[("some_option", &description)]);
+/// ```
+///
 /// Individual variables can be accessed using getter functions:
 ///
 /// ```rust
@@ -1077,11 +1206,22 @@ macro_rules! libretro_variables {
 
         impl $st {
             fn register() {
+                $st::register_with(&[]);
+            }
+
+            /// Register the variables, replacing the description of
+            /// those listed in `overrides` as `(name, description)`.
+            /// Used for options whose values are only known once the
+            /// game is loaded. The frontend doesn't allow the number
+            /// of variables to change after the first registration.
+            fn register_with(overrides: &[(&str, &::std::ffi::CStr)]) {
 
                 let variables = [
                     $($crate::libretro::Variable {
                         key: cstring!(concat!($prefix, '_', stringify!($name))),
-                        value: cstring!($str),
+                        value: overrides.iter()
+                            .find(|&&(n, _)| n == stringify!($name))
+                            .map_or(cstring!($str), |&(_, v)| v.as_ptr()),
                     }),+,
                     // End of table marker
                     $crate::libretro::Variable {
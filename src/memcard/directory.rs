@@ -0,0 +1,361 @@
+//! Memory card directory parser
+
+use encoding_rs::SHIFT_JIS;
+
+use super::{frame, checksum, BLOCK_COUNT, BLOCK_SIZE, FRAME_SIZE,
+            FILENAME_MAX_LEN, DIR_FIRST, DIR_MIDDLE, DIR_LAST,
+            NO_NEXT_BLOCK};
+
+/// Decoded card directory
+pub struct Directory {
+    /// Saves found on the card, in directory order
+    pub saves: Vec<Save>,
+    /// Inconsistencies found while parsing the directory
+    pub problems: Vec<Problem>,
+}
+
+impl Directory {
+    /// Parse the directory of the memory card image `card`
+    pub fn parse(card: &[u8]) -> Result<Directory, Error> {
+        if card.len() != BLOCK_COUNT * BLOCK_SIZE {
+            return Err(Error::BadLength(card.len()));
+        }
+
+        let header = frame(card, 0);
+
+        if &header[0..2] != b"MC" {
+            return Err(Error::NotFormatted);
+        }
+
+        let mut problems = Vec::new();
+
+        if checksum(header) != header[FRAME_SIZE - 1] {
+            problems.push(Problem::BadChecksum(0));
+        }
+
+        for block in 1..BLOCK_COUNT {
+            let entry = frame(card, block);
+
+            if checksum(entry) != entry[FRAME_SIZE - 1] {
+                problems.push(Problem::BadChecksum(block));
+            }
+        }
+
+        // Blocks claimed by a save, used to detect loops and orphans
+        let mut used = [false; BLOCK_COUNT];
+
+        let mut saves = Vec::new();
+
+        for block in 1..BLOCK_COUNT {
+            if frame(card, block)[0] != DIR_FIRST {
+                continue;
+            }
+
+            match Save::parse(card, block, &mut used) {
+                Ok(save) => {
+                    if save.size as usize != save.blocks.len() * BLOCK_SIZE {
+                        problems.push(Problem::BadSize(block));
+                    }
+
+                    saves.push(save);
+                }
+                Err(p) => problems.push(p),
+            }
+        }
+
+        for block in 1..BLOCK_COUNT {
+            let state = frame(card, block)[0];
+
+            let in_chain = state == DIR_MIDDLE || state == DIR_LAST;
+
+            if in_chain && !used[block] {
+                problems.push(Problem::Orphan(block));
+            }
+        }
+
+        Ok(Directory {
+            saves: saves,
+            problems: problems,
+        })
+    }
+
+    /// Return the saves containing a PocketStation executable
+    pub fn executables<'a>(&'a self) -> Box<Iterator<Item=&'a Save> + 'a> {
+        Box::new(self.saves.iter().filter(|s| s.executable.is_some()))
+    }
+}
+
+/// A single save spanning one or more blocks
+pub struct Save {
+    /// Blocks used by the save, in order. Blocks are numbered 1 to
+    /// 15, block 0 being the directory.
+    pub blocks: Vec<usize>,
+    /// Size of the save in bytes as stored in the directory
+    pub size: u32,
+    /// Filename in the directory entry (usually "B", a region letter,
+    /// the product code and a game-specific identifier)
+    pub filename: String,
+    /// Title displayed by the BIOS, decoded from Shift-JIS
+    pub title: String,
+    /// Icon displayed in the PlayStation memory card manager
+    pub icon: Icon,
+    /// PocketStation executable type, if any
+    pub executable: Option<Executable>,
+}
+
+impl Save {
+    /// Parse the save starting at `first_block` and mark its blocks
+    /// in `used`
+    fn parse(card: &[u8],
+             first_block: usize,
+             used: &mut [bool; BLOCK_COUNT]) -> Result<Save, Problem> {
+        let entry = frame(card, first_block);
+
+        let size =
+            (entry[4] as u32) |
+            ((entry[5] as u32) << 8) |
+            ((entry[6] as u32) << 16) |
+            ((entry[7] as u32) << 24);
+
+        let filename = &entry[0xa..0xa + FILENAME_MAX_LEN];
+        let filename_len =
+            filename.iter().position(|&b| b == 0).unwrap_or(filename.len());
+        let filename = String::from_utf8_lossy(&filename[..filename_len]);
+
+        let mut blocks = Vec::new();
+        let mut block = first_block;
+
+        loop {
+            if used[block] {
+                return Err(Problem::BrokenChain(first_block));
+            }
+
+            used[block] = true;
+            blocks.push(block);
+
+            let entry = frame(card, block);
+
+            let next = (entry[8] as u16) | ((entry[9] as u16) << 8);
+
+            if next == NO_NEXT_BLOCK {
+                break;
+            }
+
+            // Block pointers are 0-based starting from block 1
+            let next = next as usize + 1;
+
+            if next >= BLOCK_COUNT {
+                return Err(Problem::BrokenChain(first_block));
+            }
+
+            let state = frame(card, next)[0];
+
+            if state != DIR_MIDDLE && state != DIR_LAST {
+                return Err(Problem::BrokenChain(first_block));
+            }
+
+            block = next;
+        }
+
+        if frame(card, block)[0] != DIR_LAST && blocks.len() > 1 {
+            return Err(Problem::BrokenChain(first_block));
+        }
+
+        let data = &card[first_block * BLOCK_SIZE..];
+
+        let title_frame = &data[..FRAME_SIZE];
+
+        if &title_frame[0..2] != b"SC" {
+            return Err(Problem::BadTitleFrame(first_block));
+        }
+
+        let title = &title_frame[TITLE_OFFSET..TITLE_OFFSET + TITLE_MAX_LEN];
+        let title_len =
+            title.iter().position(|&b| b == 0).unwrap_or(title.len());
+        let (title, _, _) = SHIFT_JIS.decode(&title[..title_len]);
+
+        let executable =
+            match &title_frame[MCX_MAGIC_OFFSET..MCX_MAGIC_OFFSET + 4] {
+                b"MCX0" => Some(Executable::Mcx0),
+                b"MCX1" => Some(Executable::Mcx1),
+                _ => None,
+            };
+
+        Ok(Save {
+            blocks: blocks,
+            size: size,
+            filename: filename.into_owned(),
+            title: title.into_owned(),
+            icon: Icon::parse(data),
+            executable: executable,
+        })
+    }
+
+    /// Index of the first block of the save
+    pub fn first_block(&self) -> usize {
+        self.blocks[0]
+    }
+
+    /// Return the product code embedded in the filename
+    /// (e.g. "SCPS-15030"), if any
+    pub fn product_code(&self) -> Option<&str> {
+        if self.filename.len() >= 12 && self.filename.starts_with('B') {
+            self.filename.get(2..12)
+        } else {
+            None
+        }
+    }
+}
+
+/// PocketStation executable type, from the magic in the title frame
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Executable {
+    /// "MCX0"
+    Mcx0,
+    /// "MCX1"
+    Mcx1,
+}
+
+/// 16x16 4bpp icon, possibly animated
+pub struct Icon {
+    /// 16 colour palette in the PlayStation 15bit format
+    pub palette: [u16; 16],
+    /// Bitmap of each animation frame, two pixels per byte
+    pub frames: Vec<[u8; FRAME_SIZE]>,
+}
+
+impl Icon {
+    /// Parse the icon of the save starting with `data`
+    fn parse(data: &[u8]) -> Icon {
+        let mut palette = [0; 16];
+
+        for (i, c) in palette.iter_mut().enumerate() {
+            let off = ICON_PALETTE_OFFSET + i * 2;
+
+            *c = (data[off] as u16) | ((data[off + 1] as u16) << 8);
+        }
+
+        // 0x11, 0x12 and 0x13 mean respectively 1, 2 and 3 frames,
+        // anything else is treated as a single frame
+        let nframes =
+            match data[ICON_FLAG_OFFSET] {
+                0x12 => 2,
+                0x13 => 3,
+                _ => 1,
+            };
+
+        let frames =
+            (0..nframes).map(|f| {
+                let mut bitmap = [0; FRAME_SIZE];
+
+                bitmap.copy_from_slice(frame(data, f + 1));
+
+                bitmap
+            }).collect();
+
+        Icon {
+            palette: palette,
+            frames: frames,
+        }
+    }
+
+    /// Return the palette index of pixel (`x`, `y`) in frame `f`
+    pub fn pixel(&self, f: usize, x: usize, y: usize) -> u8 {
+        let b = self.frames[f][y * 8 + x / 2];
+
+        if x & 1 == 0 {
+            b & 0xf
+        } else {
+            b >> 4
+        }
+    }
+}
+
+/// Inconsistency found while parsing the directory. Block numbers
+/// are given between 1 and 15, 0 being the header frame.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Problem {
+    /// The directory frame for this block has an invalid checksum
+    BadChecksum(usize),
+    /// The block chain of the save starting at this block is invalid
+    BrokenChain(usize),
+    /// This block is marked as part of a save but no save uses it
+    Orphan(usize),
+    /// The save starting at this block doesn't have a valid title
+    /// frame
+    BadTitleFrame(usize),
+    /// The size of the save starting at this block doesn't match the
+    /// number of blocks it uses
+    BadSize(usize),
+}
+
+#[derive(Debug)]
+/// Error type for directory parsing
+pub enum Error {
+    /// The card image doesn't have the right length
+    BadLength(usize),
+    /// The card doesn't have a valid header frame
+    NotFormatted,
+}
+
+/// Offset of the icon display flag in the title frame
+const ICON_FLAG_OFFSET: usize = 0x2;
+/// Offset of the Shift-JIS title in the title frame
+const TITLE_OFFSET: usize = 0x4;
+/// Maximum length of the title in bytes
+const TITLE_MAX_LEN: usize = 64;
+/// Offset of the PocketStation executable magic in the title frame
+const MCX_MAGIC_OFFSET: usize = 0x52;
+/// Offset of the icon palette in the title frame
+const ICON_PALETTE_OFFSET: usize = 0x60;
+
+#[test]
+fn test_parse_directory() {
+    let mut card = super::blank_card();
+
+    let mut save = vec![0; BLOCK_SIZE * 2];
+
+    save[0] = b'S';
+    save[1] = b'C';
+    save[ICON_FLAG_OFFSET] = 0x12;
+    // "ＰＤＡ" in full-width Shift-JIS
+    save[TITLE_OFFSET..TITLE_OFFSET + 6]
+        .copy_from_slice(&[0x82, 0x6f, 0x82, 0x63, 0x82, 0x60]);
+    save[MCX_MAGIC_OFFSET..MCX_MAGIC_OFFSET + 4].copy_from_slice(b"MCX0");
+    save[FRAME_SIZE] = 0x21;
+
+    super::insert_save(&mut card, b"BISCPS-15030POCKET", &save).unwrap();
+
+    save[MCX_MAGIC_OFFSET..MCX_MAGIC_OFFSET + 4].copy_from_slice(b"CRD0");
+
+    super::insert_save(&mut card, b"BASCUS-94426", &save).unwrap();
+
+    let dir = Directory::parse(&card).unwrap();
+
+    assert!(dir.problems.is_empty());
+    assert_eq!(dir.saves.len(), 2);
+
+    let s = &dir.saves[0];
+
+    assert_eq!(s.blocks, vec![1, 2]);
+    assert_eq!(s.size, BLOCK_SIZE as u32 * 2);
+    assert_eq!(s.title, "ＰＤＡ");
+    assert_eq!(s.product_code(), Some("SCPS-15030"));
+    assert_eq!(s.executable, Some(Executable::Mcx0));
+    assert_eq!(s.icon.frames.len(), 2);
+    assert_eq!(s.icon.pixel(0, 0, 0), 1);
+    assert_eq!(s.icon.pixel(0, 1, 0), 2);
+
+    assert_eq!(dir.saves[1].blocks, vec![3, 4]);
+    assert_eq!(dir.saves[1].executable, None);
+    assert_eq!(dir.executables().count(), 1);
+
+    // Break the chain of the second save
+    card[4 * FRAME_SIZE] = 0xa0;
+
+    let dir = Directory::parse(&card).unwrap();
+
+    assert_eq!(dir.saves.len(), 1);
+    assert!(dir.problems.contains(&Problem::BrokenChain(3)));
+    assert!(dir.problems.contains(&Problem::BadChecksum(4)));
+}
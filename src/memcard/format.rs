@@ -0,0 +1,455 @@
+//! Memory card container formats. The emulator always works on a raw
+//! 128KB flash image, this module converts the various file formats
+//! used by card dumpers and other emulators to and from it.
+
+use std::io;
+use std::io::{Read, Write};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use pockystation::memory::flash::FLASH_SIZE;
+
+use super::{BLOCK_SIZE, FRAME_SIZE, FILENAME_MAX_LEN, DIR_FIRST};
+use super::directory::Directory;
+
+/// Memory card file format
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Format {
+    /// Raw flash dump (.mcr, .mcd, .srm...)
+    Raw,
+    /// DexDrive dump (.gme): raw card preceded by a 3904 byte header
+    Gme,
+    /// PSP export (.vmp): raw card preceded by a signed 128 byte
+    /// header
+    Vmp,
+    /// PS3 single save export (.psv)
+    Psv,
+    /// PSXGameEdit single save (.mcs): the save's directory frame
+    /// followed by its blocks
+    Mcs,
+    /// Raw single save: the save's blocks without any header, starting
+    /// with the "SC" title frame
+    RawSave,
+}
+
+/// Memory card file
+pub struct CardFile {
+    /// Path of the file the card was loaded from
+    path: PathBuf,
+    /// Container format
+    format: Format,
+    /// Original file header, written back verbatim when the card is
+    /// saved. Empty for raw cards.
+    header: Vec<u8>,
+    /// For single save formats: the location of the save in the
+    /// flash
+    single_save: Option<SingleSave>,
+}
+
+/// Location of a single save in the card we built around it
+struct SingleSave {
+    /// Filename of the save's directory entry
+    filename: String,
+    /// First block of the save
+    first_block: usize,
+    /// Number of blocks used by the save
+    blocks: usize,
+}
+
+impl CardFile {
+    /// Load the memory card file at `path`. Returns the file
+    /// description alongside the raw flash contents.
+    pub fn load(path: &Path) -> Result<(CardFile, Vec<u8>), Error> {
+        let mut contents = Vec::new();
+
+        {
+            let file = try!(File::open(path));
+
+            // Make sure we don't attempt to load a huge file into
+            // memory if we're given garbage
+            let max_len = FLASH_SIZE as u64 * 2;
+
+            try!(file.take(max_len).read_to_end(&mut contents));
+        }
+
+        CardFile::parse(path, contents)
+    }
+
+    /// Identify the format of the file `contents` loaded from `path`
+    /// and extract the flash contents
+    fn parse(path: &Path,
+             mut contents: Vec<u8>) -> Result<(CardFile, Vec<u8>), Error> {
+        let flash_len = FLASH_SIZE as usize;
+
+        let (format, header_len) =
+            if has_container(&contents, GME_MAGIC, GME_HEADER_LEN) {
+                (Format::Gme, GME_HEADER_LEN)
+            } else if has_container(&contents, VMP_MAGIC, VMP_HEADER_LEN) {
+                (Format::Vmp, VMP_HEADER_LEN)
+            } else if contents.starts_with(PSV_MAGIC) {
+                return CardFile::load_single_save(path,
+                                                  Format::Psv,
+                                                  contents,
+                                                  PSV_HEADER_LEN,
+                                                  PSV_FILENAME_OFFSET);
+            } else if contents.len() == flash_len {
+                (Format::Raw, 0)
+            } else if is_save(&contents) {
+                return CardFile::load_raw_save(path, contents);
+            } else if contents.len() > FRAME_SIZE &&
+                contents[0] == DIR_FIRST &&
+                is_save(&contents[FRAME_SIZE..]) {
+                    return CardFile::load_single_save(path,
+                                                      Format::Mcs,
+                                                      contents,
+                                                      FRAME_SIZE,
+                                                      MCS_FILENAME_OFFSET);
+            } else {
+                return Err(Error::UnknownFormat(contents.len()));
+            };
+
+        let flash = contents.split_off(header_len);
+
+        let card = CardFile {
+            path: path.to_path_buf(),
+            format: format,
+            header: contents,
+            single_save: None,
+        };
+
+        Ok((card, flash))
+    }
+
+    /// Load a single save preceded by a `header_len` byte header
+    /// containing the NUL-padded filename at `filename_offset`
+    fn load_single_save(path: &Path,
+                        format: Format,
+                        mut contents: Vec<u8>,
+                        header_len: usize,
+                        filename_offset: usize)
+                        -> Result<(CardFile, Vec<u8>), Error> {
+        if contents.len() <= header_len {
+            return Err(Error::BadSingleSave(format));
+        }
+
+        let save = contents.split_off(header_len);
+
+        if !is_save(&save) {
+            return Err(Error::BadSingleSave(format));
+        }
+
+        let filename = &contents[filename_offset..
+                                 filename_offset + FILENAME_MAX_LEN];
+        let filename_len =
+            filename.iter().position(|&b| b == 0).unwrap_or(filename.len());
+        let filename = filename[..filename_len].to_vec();
+
+        CardFile::build_card(path, format, contents, &filename, &save)
+    }
+
+    /// Load a raw single save. The files don't contain any header,
+    /// tools usually name them after the save so we use that as a
+    /// filename.
+    fn load_raw_save(path: &Path,
+                     save: Vec<u8>) -> Result<(CardFile, Vec<u8>), Error> {
+        let stem =
+            path.file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or(String::new());
+
+        let mut filename: Vec<u8> =
+            stem.bytes()
+            .filter(|b| b.is_ascii_alphanumeric() || *b == b'-' || *b == b'_')
+            .take(FILENAME_MAX_LEN)
+            .collect();
+
+        if filename.is_empty() {
+            filename.extend_from_slice(DEFAULT_FILENAME);
+        }
+
+        CardFile::build_card(path, Format::RawSave, Vec::new(), &filename, &save)
+    }
+
+    /// Build a blank card and place the single save `save` in the
+    /// first free blocks. The save ends up being the first (and
+    /// only) entry in the directory so it's the application the BIOS
+    /// boots.
+    fn build_card(path: &Path,
+                  format: Format,
+                  header: Vec<u8>,
+                  filename: &[u8],
+                  save: &[u8]) -> Result<(CardFile, Vec<u8>), Error> {
+        let mut flash = super::blank_card();
+
+        let first_block = try!(super::insert_save(&mut flash, filename, save));
+        let blocks = save.len() / BLOCK_SIZE;
+
+        info!("Built memory card around single save {:?} ({} blocks)",
+              String::from_utf8_lossy(filename), blocks);
+
+        let card = CardFile {
+            path: path.to_path_buf(),
+            format: format,
+            header: header,
+            single_save: Some(SingleSave {
+                filename: String::from_utf8_lossy(filename).into_owned(),
+                first_block: first_block,
+                blocks: blocks,
+            }),
+        };
+
+        Ok((card, flash))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// Write `flash` back to the card file, using the original
+    /// container format
+    pub fn save(&self, flash: &[u8]) -> Result<(), Error> {
+        match self.format {
+            // We'd need the console keys to sign the files, the PSP
+            // and PS3 refuse to load them otherwise
+            Format::Vmp | Format::Psv =>
+                return Err(Error::SignedContainer(self.format)),
+            _ => (),
+        }
+
+        let data =
+            match self.single_save {
+                Some(ref save) => {
+                    // The save can't grow or move, some headers contain
+                    // its size
+                    if !save.is_in_place(flash) {
+                        return Err(Error::SingleSaveMoved);
+                    }
+
+                    let start = save.first_block * BLOCK_SIZE;
+                    let end = start + save.blocks * BLOCK_SIZE;
+
+                    &flash[start..end]
+                }
+                None => flash,
+            };
+
+        // Write to a temporary file first and then move it in place,
+        // this way we don't end up with a truncated memory card if
+        // something goes wrong halfway through
+        let tmp_path = self.path.with_extension("tmp");
+
+        let res =
+            File::create(&tmp_path)
+            .and_then(|mut f| {
+                try!(f.write_all(&self.header));
+                try!(f.write_all(data));
+                f.sync_all()
+            })
+            .and_then(|_| ::std::fs::rename(&tmp_path, &self.path));
+
+        if res.is_err() {
+            let _ = ::std::fs::remove_file(&tmp_path);
+        }
+
+        res.map_err(|e| Error::IoError(e))
+    }
+}
+
+impl SingleSave {
+    /// Return true if the save still occupies the same contiguous
+    /// blocks in `flash`
+    fn is_in_place(&self, flash: &[u8]) -> bool {
+        let dir =
+            match Directory::parse(flash) {
+                Ok(d) => d,
+                Err(_) => return false,
+            };
+
+        let expected: Vec<usize> =
+            (self.first_block..self.first_block + self.blocks).collect();
+
+        dir.saves.iter().any(|s| s.filename == self.filename &&
+                             s.blocks == expected)
+    }
+}
+
+/// Return true if `data` looks like a save: a whole number of blocks
+/// starting with the "SC" title frame. A save can't use all 16
+/// blocks since the first one holds the directory.
+fn is_save(data: &[u8]) -> bool {
+    data.starts_with(b"SC") &&
+        !data.is_empty() &&
+        data.len() % BLOCK_SIZE == 0 &&
+        data.len() < FLASH_SIZE as usize
+}
+
+/// Return true if `contents` is a raw card preceded by a header of
+/// `header_len` bytes starting with `magic`
+fn has_container(contents: &[u8], magic: &[u8], header_len: usize) -> bool {
+    contents.starts_with(magic) &&
+        contents.len() == header_len + FLASH_SIZE as usize
+}
+
+#[derive(Debug)]
+/// Error type for memory card file loading and saving
+pub enum Error {
+    /// Error while reading or writing the file
+    IoError(io::Error),
+    /// The file format couldn't be identified, got a file of this
+    /// many bytes
+    UnknownFormat(usize),
+    /// Invalid single save file
+    BadSingleSave(Format),
+    /// Couldn't place the save in the card
+    CardError(super::Error),
+    /// The container is signed and can't be written back
+    SignedContainer(Format),
+    /// The single save grew, moved or was deleted, it can't be
+    /// written back
+    SingleSaveMoved,
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::IoError(e)
+    }
+}
+
+impl From<super::Error> for Error {
+    fn from(e: super::Error) -> Error {
+        Error::CardError(e)
+    }
+}
+
+/// Magic string at the start of DexDrive files
+const GME_MAGIC: &'static [u8] = b"123-456-STD";
+/// Length of the DexDrive header
+const GME_HEADER_LEN: usize = 0xf40;
+/// Magic string at the start of PSP files
+const VMP_MAGIC: &'static [u8] = b"\0PMV";
+/// Length of the PSP header
+const VMP_HEADER_LEN: usize = 0x80;
+/// Magic string at the start of PS3 files
+const PSV_MAGIC: &'static [u8] = b"\0VSP";
+/// Length of the PS3 header, the save blocks follow immediately
+const PSV_HEADER_LEN: usize = 0x84;
+/// Offset of the save's filename in the PS3 header
+const PSV_FILENAME_OFFSET: usize = 0x64;
+/// Offset of the save's filename in the PSXGameEdit header (which is
+/// just a copy of the save's directory frame)
+const MCS_FILENAME_OFFSET: usize = 0xa;
+/// Filename used for raw saves when we can't make one from the file
+/// name
+const DEFAULT_FILENAME: &'static [u8] = b"POCKETSTATION";
+
+#[cfg(test)]
+fn with_header(magic: &[u8], header_len: usize, data: &[u8]) -> Vec<u8> {
+    let mut contents = vec![0; header_len];
+
+    contents[..magic.len()].copy_from_slice(magic);
+    contents.extend_from_slice(data);
+
+    contents
+}
+
+#[test]
+fn test_detect_format() {
+    let path = Path::new("BISCPS-15030.bin");
+    let card = super::blank_card();
+
+    let mut save = vec![0x5a; BLOCK_SIZE * 2];
+    save[..2].copy_from_slice(b"SC");
+
+    let detect = |contents: Vec<u8>| {
+        let (file, flash) = CardFile::parse(path, contents).unwrap();
+
+        assert_eq!(flash.len(), FLASH_SIZE as usize);
+
+        file.format()
+    };
+
+    assert_eq!(detect(card.clone()), Format::Raw);
+    assert_eq!(detect(with_header(GME_MAGIC, GME_HEADER_LEN, &card)),
+               Format::Gme);
+    assert_eq!(detect(with_header(VMP_MAGIC, VMP_HEADER_LEN, &card)),
+               Format::Vmp);
+    assert_eq!(detect(with_header(PSV_MAGIC, PSV_HEADER_LEN, &save)),
+               Format::Psv);
+    assert_eq!(detect(with_header(&[DIR_FIRST], FRAME_SIZE, &save)),
+               Format::Mcs);
+    assert_eq!(detect(save.clone()), Format::RawSave);
+
+    // A truncated container is not a raw card
+    let truncated = with_header(GME_MAGIC, GME_HEADER_LEN, &card[1..]);
+
+    assert!(CardFile::parse(path, truncated).is_err());
+    assert!(CardFile::parse(path, vec![0; 1234]).is_err());
+    assert!(CardFile::parse(path, save[..FRAME_SIZE].to_vec()).is_err());
+}
+
+#[test]
+fn test_signed_writeback() {
+    let path = Path::new("card.vmp");
+    let card = super::blank_card();
+
+    let (file, flash) =
+        CardFile::parse(path,
+                        with_header(VMP_MAGIC, VMP_HEADER_LEN, &card))
+        .unwrap();
+
+    // Refused before touching the filesystem
+    match file.save(&flash) {
+        Err(Error::SignedContainer(Format::Vmp)) => (),
+        r => panic!("Unexpected result {:?}", r),
+    }
+}
+
+#[test]
+fn test_single_save_writeback() {
+    let mut save = vec![0x5a; BLOCK_SIZE * 2];
+    save[..2].copy_from_slice(b"SC");
+
+    let mcs = with_header(&[DIR_FIRST], FRAME_SIZE, &save);
+    let raw = save.clone();
+
+    for contents in vec![mcs, raw] {
+        let (file, flash) =
+            CardFile::parse(Path::new("BISCPS-15030.mcs"), contents).unwrap();
+
+        assert!(file.single_save.as_ref().unwrap().is_in_place(&flash));
+
+        // Deleted
+        let mut moved = super::blank_card();
+
+        assert!(!file.single_save.as_ref().unwrap().is_in_place(&moved));
+
+        // Moved after another save
+        let filename = file.single_save.as_ref().unwrap().filename.clone();
+
+        super::insert_save(&mut moved, b"BASCUS-94426", &save[..BLOCK_SIZE])
+            .unwrap();
+        super::insert_save(&mut moved, filename.as_bytes(), &save).unwrap();
+
+        assert!(!file.single_save.as_ref().unwrap().is_in_place(&moved));
+
+        // Grown
+        let mut grown = super::blank_card();
+        let mut big = save.clone();
+
+        big.extend_from_slice(&save[..BLOCK_SIZE]);
+
+        super::insert_save(&mut grown, filename.as_bytes(), &big).unwrap();
+
+        assert!(!file.single_save.as_ref().unwrap().is_in_place(&grown));
+
+        // Refused before touching the filesystem
+        match file.save(&grown) {
+            Err(Error::SingleSaveMoved) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+}
@@ -0,0 +1,211 @@
+//! PlayStation memory card helpers. The PocketStation flash uses the
+//! exact same layout as a regular memory card: 16 blocks of 8KB, each
+//! block made of 64 frames of 128 bytes. Block 0 holds the header and
+//! directory frames, blocks 1 to 15 hold the saves.
+
+use pockystation::memory::flash::FLASH_SIZE;
+
+pub mod format;
+pub mod directory;
+
+/// Size of a single frame in bytes
+pub const FRAME_SIZE: usize = 128;
+/// Size of a single block in bytes
+pub const BLOCK_SIZE: usize = 64 * FRAME_SIZE;
+/// Total number of blocks on the card, including the directory
+/// block
+pub const BLOCK_COUNT: usize = FLASH_SIZE as usize / BLOCK_SIZE;
+/// Maximum length of a save's filename, not including the NUL
+/// terminator
+pub const FILENAME_MAX_LEN: usize = 20;
+
+/// Directory entry allocation state for the first block of a save
+const DIR_FIRST: u8 = 0x51;
+/// Directory entry allocation state for the blocks in the middle of
+/// a save
+const DIR_MIDDLE: u8 = 0x52;
+/// Directory entry allocation state for the last block of a save
+const DIR_LAST: u8 = 0x53;
+/// Directory entry allocation state for a free block
+const DIR_FREE: u8 = 0xa0;
+/// "Next block" pointer value used to mark the end of a chain
+const NO_NEXT_BLOCK: u16 = 0xffff;
+/// First frame of the broken sector list
+const BROKEN_LIST_FIRST: usize = 16;
+/// Number of entries in the broken sector list
+const BROKEN_LIST_LEN: usize = 20;
+/// The "write test" frame, a copy of the header frame
+const WRITE_TEST_FRAME: usize = 63;
+
+/// Build a blank, formatted memory card image
+pub fn blank_card() -> Vec<u8> {
+    let mut card = vec![0; FLASH_SIZE as usize];
+
+    {
+        let header = frame_mut(&mut card, 0);
+
+        header[0] = b'M';
+        header[1] = b'C';
+        update_checksum(header);
+    }
+
+    for block in 1..BLOCK_COUNT {
+        let entry = frame_mut(&mut card, block);
+
+        entry[0] = DIR_FREE;
+        set_next_block(entry, NO_NEXT_BLOCK);
+        update_checksum(entry);
+    }
+
+    for i in 0..BROKEN_LIST_LEN {
+        let entry = frame_mut(&mut card, BROKEN_LIST_FIRST + i);
+
+        for b in &mut entry[0..4] {
+            *b = 0xff;
+        }
+        set_next_block(entry, NO_NEXT_BLOCK);
+        update_checksum(entry);
+    }
+
+    let (dir, write_test) = card.split_at_mut(WRITE_TEST_FRAME * FRAME_SIZE);
+
+    write_test[..FRAME_SIZE].copy_from_slice(&dir[..FRAME_SIZE]);
+
+    card
+}
+
+/// Store the single save `save` in the first free blocks of `card`
+/// and create the corresponding directory entries. `save` must be
+/// made of whole blocks. Returns the index of the first block used.
+pub fn insert_save(card: &mut [u8],
+                   filename: &[u8],
+                   save: &[u8]) -> Result<usize, Error> {
+    if save.is_empty() || save.len() % BLOCK_SIZE != 0 {
+        return Err(Error::BadSaveLength(save.len()));
+    }
+
+    if filename.len() > FILENAME_MAX_LEN {
+        return Err(Error::FilenameTooLong(filename.len()));
+    }
+
+    let nblocks = save.len() / BLOCK_SIZE;
+
+    let free: Vec<usize> =
+        (1..BLOCK_COUNT)
+        .filter(|&b| frame(card, b)[0] == DIR_FREE)
+        .take(nblocks)
+        .collect();
+
+    if free.len() < nblocks {
+        return Err(Error::CardFull(nblocks));
+    }
+
+    for (i, &block) in free.iter().enumerate() {
+        let data = &save[i * BLOCK_SIZE..(i + 1) * BLOCK_SIZE];
+
+        card[block * BLOCK_SIZE..(block + 1) * BLOCK_SIZE]
+            .copy_from_slice(data);
+
+        let entry = frame_mut(card, block);
+
+        for b in entry.iter_mut() {
+            *b = 0;
+        }
+
+        entry[0] =
+            if i == 0 {
+                DIR_FIRST
+            } else if i == nblocks - 1 {
+                DIR_LAST
+            } else {
+                DIR_MIDDLE
+            };
+
+        if i == 0 {
+            let size = save.len() as u32;
+
+            entry[4] = size as u8;
+            entry[5] = (size >> 8) as u8;
+            entry[6] = (size >> 16) as u8;
+            entry[7] = (size >> 24) as u8;
+        }
+
+        let next =
+            match free.get(i + 1) {
+                Some(&n) => (n - 1) as u16,
+                None => NO_NEXT_BLOCK,
+            };
+
+        set_next_block(entry, next);
+
+        entry[0xa..0xa + filename.len()].copy_from_slice(filename);
+
+        update_checksum(entry);
+    }
+
+    Ok(free[0])
+}
+
+/// Return frame number `n` of `card`
+fn frame(card: &[u8], n: usize) -> &[u8] {
+    &card[n * FRAME_SIZE..(n + 1) * FRAME_SIZE]
+}
+
+/// Return frame number `n` of `card`
+fn frame_mut(card: &mut [u8], n: usize) -> &mut [u8] {
+    &mut card[n * FRAME_SIZE..(n + 1) * FRAME_SIZE]
+}
+
+/// Set the "next block" pointer of a directory or broken sector
+/// entry. Block pointers are 0-based starting from block 1.
+fn set_next_block(entry: &mut [u8], next: u16) {
+    entry[8] = next as u8;
+    entry[9] = (next >> 8) as u8;
+}
+
+/// Compute the checksum of a header or directory frame and store it
+/// in the last byte
+fn update_checksum(frame: &mut [u8]) {
+    frame[FRAME_SIZE - 1] = checksum(frame);
+}
+
+/// The checksum of a header or directory frame is the XOR of the
+/// first 127 bytes
+fn checksum(frame: &[u8]) -> u8 {
+    frame[..FRAME_SIZE - 1].iter().fold(0, |c, &b| c ^ b)
+}
+
+#[derive(Debug)]
+/// Error type for memory card manipulation
+pub enum Error {
+    /// Saves must be made of whole blocks, got this many bytes
+    BadSaveLength(usize),
+    /// Filename is longer than `FILENAME_MAX_LEN`
+    FilenameTooLong(usize),
+    /// Not enough free blocks to store a save of this many blocks
+    CardFull(usize),
+}
+
+#[test]
+fn test_insert_save() {
+    let mut card = blank_card();
+
+    assert_eq!(checksum(frame(&card, 0)), card[FRAME_SIZE - 1]);
+
+    let save = vec![0x5a; BLOCK_SIZE * 2];
+
+    assert_eq!(insert_save(&mut card, b"BASCUS-94426", &save).unwrap(), 1);
+    assert_eq!(insert_save(&mut card, b"BISCPS-15030", &save).unwrap(), 3);
+
+    assert_eq!(frame(&card, 1)[0], DIR_FIRST);
+    assert_eq!(&frame(&card, 1)[4..10], &[0x00, 0x40, 0, 0, 1, 0]);
+    assert_eq!(frame(&card, 2)[0], DIR_LAST);
+    assert_eq!(&frame(&card, 2)[8..10], &[0xff, 0xff]);
+    assert_eq!(&frame(&card, 3)[0xa..0x16], b"BISCPS-15030");
+    assert_eq!(frame(&card, 5)[0], DIR_FREE);
+    assert_eq!(card[BLOCK_SIZE * 4], 0x5a);
+
+    let big = vec![0; BLOCK_SIZE * 12];
+
+    assert!(insert_save(&mut card, b"BASLUS-00000", &big).is_err());
+}
@@ -0,0 +1,186 @@
+//! Savestate metadata
+//!
+//! Since format version 5 savestates store a `Metadata` struct right
+//! after the header, before the emulator state. Frontends and
+//! launchers can use `read` to show a preview of a savestate without
+//! decoding the whole emulator state.
+
+use std::io::Read;
+
+use serde::Deserialize;
+
+use pockystation::cpu::Cpu;
+
+use savestate;
+use memcard::directory::Directory;
+
+/// Information describing a savestate
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Metadata {
+    /// Contents of the 32x32 LCD when the savestate was created, one
+    /// `u32` per row. Bit `x` of row `y` is the pixel at `(x, y)`,
+    /// pixels are lit when their bit is 0.
+    pub lcd: Vec<u32>,
+    /// True if the software had requested the display to be rotated
+    pub lcd_rotated: bool,
+    /// Date and time of the emulated RTC
+    pub rtc: RtcDate,
+    /// Application selected on the memory card, if any
+    pub app: Option<App>,
+    /// Host time when the savestate was created, in seconds since the
+    /// Unix epoch
+    pub timestamp: i64,
+}
+
+impl Metadata {
+    /// Build the metadata describing the current state of `cpu`.
+    /// `boot_app` is the directory filename of the application
+    /// selected with the `boot_app` option, if any.
+    pub fn capture(cpu: &Cpu, boot_app: Option<&str>) -> Metadata {
+        let inter = cpu.interconnect();
+
+        let rtc = inter.rtc();
+
+        // The century is not stored in the RTC, see `sync_host_rtc`
+        let century = bcd_to_binary(inter.ram().data()[0xcf]) as u16;
+        let year = bcd_to_binary(rtc.year().bcd()) as u16;
+
+        Metadata {
+            lcd: ::lcd_rows(cpu).to_vec(),
+            lcd_rotated: inter.lcd().rotated(),
+            rtc: RtcDate {
+                year: century * 100 + year,
+                month: bcd_to_binary(rtc.month().bcd()),
+                day: bcd_to_binary(rtc.day().bcd()),
+                week_day: bcd_to_binary(rtc.week_day().bcd()),
+                hours: bcd_to_binary(rtc.hours().bcd()),
+                minutes: bcd_to_binary(rtc.minutes().bcd()),
+                seconds: bcd_to_binary(rtc.seconds().bcd()),
+            },
+            app: App::selected(inter.flash().data(), boot_app),
+            timestamp: ::time::get_time().sec,
+        }
+    }
+}
+
+/// Date and time of the emulated RTC
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct RtcDate {
+    pub year: u16,
+    /// 1 to 12
+    pub month: u8,
+    /// 1 to 31
+    pub day: u8,
+    /// 1 (Sunday) to 7
+    pub week_day: u8,
+    pub hours: u8,
+    pub minutes: u8,
+    pub seconds: u8,
+}
+
+/// Application on the memory card
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct App {
+    /// First block of the application
+    pub block: u32,
+    /// Title of the application as listed in the directory
+    pub title: String,
+}
+
+impl App {
+    /// Return the application selected on the card: the one in
+    /// `boot_app` if set, otherwise the first one since that's where
+    /// the BIOS menu starts.
+    fn selected(flash: &[u8], boot_app: Option<&str>) -> Option<App> {
+        let dir =
+            match Directory::parse(flash) {
+                Ok(d) => d,
+                Err(_) => return None,
+            };
+
+        let mut executables = dir.executables();
+
+        let save =
+            match boot_app {
+                Some(f) => executables.find(|s| s.filename == f),
+                None => executables.next(),
+            };
+
+        save.map(|s| App {
+            block: s.first_block() as u32,
+            title: s.title.trim().into(),
+        })
+    }
+}
+
+/// Read the header and metadata of the savestate in `reader` without
+/// decoding the emulator state. The metadata is `None` for savestates
+/// created before it was introduced.
+pub fn read(reader: &mut Read)
+            -> Result<(savestate::Header, Option<Metadata>),
+                      savestate::Error> {
+    let mut decoder = try!(savestate::Decoder::new(reader));
+
+    let header = decoder.header().clone();
+
+    let metadata = try!(decode(&mut decoder, &header));
+
+    Ok((header, metadata))
+}
+
+/// Decode the metadata following the header, if the savestate has one
+pub fn decode(decoder: &mut savestate::Decoder,
+              header: &savestate::Header)
+              -> Result<Option<Metadata>, savestate::Error> {
+    if header.version >= 5 {
+        Metadata::deserialize(decoder).map(Some)
+    } else {
+        Ok(None)
+    }
+}
+
+fn bcd_to_binary(bcd: u8) -> u8 {
+    (bcd >> 4) * 10 + (bcd & 0xf)
+}
+
+#[test]
+fn test_read() {
+    use serde::Serialize;
+
+    let metadata = Metadata {
+        lcd: (0..32).map(|y| !(1 << y)).collect(),
+        lcd_rotated: true,
+        rtc: RtcDate {
+            year: 1999,
+            month: 1,
+            day: 23,
+            week_day: 7,
+            hours: 12,
+            minutes: 34,
+            seconds: 56,
+        },
+        app: Some(App { block: 3, title: "Chocobo".into() }),
+        timestamp: 916_000_000,
+    };
+
+    let mut state = Vec::new();
+
+    {
+        let mut header = savestate::Header::new("test", "");
+        header.compressed = true;
+
+        let mut e = savestate::Encoder::new(&mut state, &header).unwrap();
+
+        metadata.serialize(&mut e).unwrap();
+        // Stands for the emulator state, it mustn't be read
+        vec![0xffu8; 0x1000].serialize(&mut e).unwrap();
+        e.finish().unwrap();
+    }
+
+    let (header, read_back) = read(&mut &state[..]).unwrap();
+
+    assert!(header.compressed);
+    assert_eq!(read_back, Some(metadata));
+
+    assert_eq!(bcd_to_binary(0x59), 59);
+}
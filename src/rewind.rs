@@ -0,0 +1,277 @@
+//! Rewind buffer
+//!
+//! Snapshots are regular savestates. We only keep the most recent one
+//! in full, older snapshots are stored as deltas: each delta contains
+//! what's needed to rebuild a snapshot from the one that followed
+//! it. Consecutive snapshots are nearly identical so the deltas are
+//! tiny.
+//!
+//! A delta is the XOR of the two snapshots, run-length encoded. It
+//! starts with the length of the target snapshot followed by a
+//! sequence of `<zero run length> <literal length> <literal bytes>`
+//! chunks. Lengths are stored as LEB128 varints. If the target is
+//! longer than the source the source is treated as if it was padded
+//! with zeroes.
+
+use std::collections::VecDeque;
+
+/// Ring buffer of snapshots
+pub struct Rewind {
+    /// Most recent snapshot, empty if there's none
+    latest: Vec<u8>,
+    /// Deltas rebuilding each snapshot from the following one, oldest
+    /// first
+    deltas: VecDeque<Vec<u8>>,
+    /// Maximum number of deltas, 0 if rewind is disabled
+    depth: usize,
+}
+
+impl Rewind {
+    pub fn new() -> Rewind {
+        Rewind {
+            latest: Vec::new(),
+            deltas: VecDeque::new(),
+            depth: 0,
+        }
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Change the maximum number of snapshots that can be restored,
+    /// dropping the oldest ones if the buffer is too big. Setting the
+    /// depth to 0 disables rewind and frees the buffer.
+    pub fn set_depth(&mut self, depth: usize) {
+        self.depth = depth;
+
+        if depth == 0 {
+            self.clear();
+        } else {
+            self.trim();
+        }
+    }
+
+    /// Drop all snapshots
+    pub fn clear(&mut self) {
+        self.latest = Vec::new();
+        self.deltas.clear();
+    }
+
+    /// Number of snapshots that can be restored with `pop`
+    pub fn len(&self) -> usize {
+        self.deltas.len()
+    }
+
+    /// Add a new snapshot to the buffer
+    pub fn push(&mut self, snapshot: Vec<u8>) {
+        if self.depth == 0 {
+            return;
+        }
+
+        if !self.latest.is_empty() {
+            let delta = encode_delta(&snapshot, &self.latest);
+
+            self.deltas.push_back(delta);
+            self.trim();
+        }
+
+        self.latest = snapshot;
+    }
+
+    /// Go back one snapshot and return it. Returns `None` if there's
+    /// no older snapshot.
+    pub fn pop(&mut self) -> Option<&[u8]> {
+        let delta =
+            match self.deltas.pop_back() {
+                Some(d) => d,
+                None => return None,
+            };
+
+        match apply_delta(&self.latest, &delta) {
+            Some(previous) => {
+                self.latest = previous;
+                Some(&self.latest)
+            }
+            None => {
+                // Can't happen unless we have a bug in the delta
+                // encoder, the rest of the chain is unusable
+                error!("Corrupted rewind buffer, dropping it");
+                self.clear();
+                None
+            }
+        }
+    }
+
+    fn trim(&mut self) {
+        while self.deltas.len() > self.depth {
+            self.deltas.pop_front();
+        }
+    }
+}
+
+/// Build the delta to rebuild `to` from `from`
+fn encode_delta(from: &[u8], to: &[u8]) -> Vec<u8> {
+    let mut delta = Vec::new();
+
+    push_varint(&mut delta, to.len());
+
+    let xor = |i: usize| to[i] ^ from.get(i).cloned().unwrap_or(0);
+
+    let mut pos = 0;
+
+    while pos < to.len() {
+        let start = pos;
+
+        while pos < to.len() && xor(pos) == 0 {
+            pos += 1;
+        }
+
+        push_varint(&mut delta, pos - start);
+
+        let start = pos;
+
+        while pos < to.len() && xor(pos) != 0 {
+            pos += 1;
+        }
+
+        push_varint(&mut delta, pos - start);
+
+        for i in start..pos {
+            delta.push(xor(i));
+        }
+    }
+
+    delta
+}
+
+/// Rebuild a snapshot from `from` and `delta`. Returns `None` if the
+/// delta is invalid.
+fn apply_delta(from: &[u8], delta: &[u8]) -> Option<Vec<u8>> {
+    let mut delta = delta;
+
+    let len =
+        match read_varint(&mut delta) {
+            Some(l) => l,
+            None => return None,
+        };
+
+    let mut to = Vec::with_capacity(len);
+
+    to.extend_from_slice(&from[..len.min(from.len())]);
+    to.resize(len, 0);
+
+    let mut pos = 0;
+
+    while pos < len {
+        let (zeroes, literal) =
+            match (read_varint(&mut delta), read_varint(&mut delta)) {
+                (Some(z), Some(l)) => (z, l),
+                _ => return None,
+            };
+
+        pos += zeroes;
+
+        // The encoder never generates empty chunks
+        let bad_chunk =
+            zeroes + literal == 0 ||
+            literal > delta.len() ||
+            pos + literal > len;
+
+        if bad_chunk {
+            return None;
+        }
+
+        for (b, x) in to[pos..pos + literal].iter_mut().zip(delta) {
+            *b ^= *x;
+        }
+
+        delta = &delta[literal..];
+        pos += literal;
+    }
+
+    if pos != len || !delta.is_empty() {
+        return None;
+    }
+
+    Some(to)
+}
+
+fn push_varint(out: &mut Vec<u8>, mut v: usize) {
+    while v >= 0x80 {
+        out.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+
+    out.push(v as u8);
+}
+
+fn read_varint(input: &mut &[u8]) -> Option<usize> {
+    let mut v = 0usize;
+    let mut shift = 0;
+
+    loop {
+        let b =
+            match input.split_first() {
+                Some((&b, rest)) => {
+                    *input = rest;
+                    b
+                }
+                None => return None,
+            };
+
+        if shift >= 64 {
+            return None;
+        }
+
+        v |= ((b & 0x7f) as usize) << shift;
+
+        if b & 0x80 == 0 {
+            return Some(v);
+        }
+
+        shift += 7;
+    }
+}
+
+#[test]
+fn test_rewind() {
+    let snapshot = |i: usize| -> Vec<u8> {
+        let mut s = vec![0x55; 0x1000 + i * 3];
+
+        s[i * 17] = i as u8;
+        s[0x800] = !(i as u8);
+
+        s
+    };
+
+    let mut rewind = Rewind::new();
+
+    rewind.push(snapshot(0));
+    assert_eq!(rewind.len(), 0);
+
+    rewind.set_depth(3);
+
+    for i in 0..6 {
+        rewind.push(snapshot(i));
+    }
+
+    assert_eq!(rewind.len(), 3);
+    assert!(rewind.deltas.iter().all(|d| d.len() < 32));
+
+    for i in (2..5).rev() {
+        assert_eq!(rewind.pop(), Some(&snapshot(i)[..]));
+    }
+
+    assert_eq!(rewind.pop(), None);
+
+    // Capture resumes from the restored snapshot
+    rewind.push(snapshot(40));
+    assert_eq!(rewind.pop(), Some(&snapshot(2)[..]));
+
+    assert_eq!(apply_delta(&[1, 2, 3], &[2, 0, 1]), None);
+    assert_eq!(apply_delta(&[1, 2, 3], &[2, 1, 0, 0, 0]), None);
+    assert_eq!(apply_delta(&[1, 2, 3], &[2, 2, 0]), Some(vec![1, 2]));
+    assert_eq!(apply_delta(&[], &encode_delta(&[], &[0, 0, 7])),
+               Some(vec![0, 0, 7]));
+}
@@ -1,40 +1,258 @@
 use std::io;
+use std::io::Read;
+use std::fmt;
+
+use serde::{ser, de};
+use serde::ser::Serialize;
+use serde::de::{Visitor, DeserializeSeed, DeserializeOwned, IntoDeserializer};
+
+use rustc_serialize::{Encodable, Decodable};
+
+/// Savestate header, stored right after the magic
+#[derive(Clone, Debug)]
+pub struct Header {
+    /// Savestate format version
+    pub version: u32,
+    /// Version of the emulator core that created the savestate
+    pub core_version: String,
+    /// SHA-1 of the BIOS in use when the savestate was created
+    pub bios_hash: String,
+    /// SHA-1 of the directory block of the memory card in use when
+    /// the savestate was created, empty if unknown
+    pub card_hash: String,
+    /// True if the payload following the header is compressed
+    pub compressed: bool,
+    /// True if symbols are stored as 32bit tags instead of strings
+    pub compact: bool,
+}
+
+impl Header {
+    pub fn new(core_version: &str, bios_hash: &str) -> Header {
+        Header {
+            version: VERSION,
+            core_version: core_version.into(),
+            bios_hash: bios_hash.into(),
+            card_hash: String::new(),
+            compressed: false,
+            compact: false,
+        }
+    }
+
+    /// Header used for savestates created before the header was
+    /// introduced
+    fn legacy() -> Header {
+        Header {
+            version: 0,
+            core_version: "unknown".into(),
+            bios_hash: String::new(),
+            card_hash: String::new(),
+            compressed: false,
+            compact: false,
+        }
+    }
+}
+
+impl Serialize for Header {
+    fn serialize<S>(&self, s: S) -> Result<S::Ok, S::Error>
+        where S: ser::Serializer {
+        use serde::ser::SerializeStruct;
+
+        // The card hash was introduced in version 7
+        let has_card_hash = self.version >= 7;
+
+        let len = if has_card_hash { 6 } else { 5 };
+
+        let mut st = try!(s.serialize_struct("Header", len));
+
+        try!(st.serialize_field("version", &self.version));
+        try!(st.serialize_field("core_version", &self.core_version));
+        try!(st.serialize_field("bios_hash", &self.bios_hash));
+        try!(st.serialize_field("compressed", &self.compressed));
+        try!(st.serialize_field("compact", &self.compact));
+
+        if has_card_hash {
+            try!(st.serialize_field("card_hash", &self.card_hash));
+        }
+
+        st.end()
+    }
+}
+
+/// Savestate migrations describing how to load savestates created
+/// before fields were added to or removed from the emulator state.
+/// Fields are identified by their struct and field name.
+pub struct Migrations {
+    /// Encoded value used for fields missing from the savestate, in
+    /// the verbose and compact encodings: `(struct, field, values)`
+    defaults: Vec<(&'static str, &'static str, [Vec<u8>; 2])>,
+    /// Decoders used to skip the value of fields that no longer exist:
+    /// `(struct, field, skip)`
+    skips: Vec<(&'static str, &'static str, SkipFn)>,
+}
+
+/// Function used to skip the value of a removed field
+type SkipFn = fn(&mut Decoder) -> Result<(), Error>;
+
+impl Migrations {
+    pub fn new() -> Migrations {
+        Migrations {
+            defaults: Vec::new(),
+            skips: Vec::new(),
+        }
+    }
+
+    /// Use `value` for the field `field` of struct `st` if it's
+    /// missing from the savestate
+    pub fn default_field<T>(&mut self,
+                            st: &'static str,
+                            field: &'static str,
+                            value: &T) -> Result<(), Error>
+        where T: Encodable {
+
+        // We don't know which encoding the savestate will use yet
+        let verbose = try!(encode_value(value, false));
+        let compact = try!(encode_value(value, true));
+
+        self.defaults.push((st, field, [verbose, compact]));
+
+        Ok(())
+    }
+
+    /// Ignore the field `field` of struct `st` of type `T` if it's
+    /// found in the savestate
+    pub fn skip_field<T>(&mut self, st: &'static str, field: &'static str)
+        where T: Decodable {
+
+        self.skips.push((st, field, skip_value::<T>));
+    }
+
+    fn default(&self,
+               st: &str,
+               field: &str,
+               compact: bool) -> Option<&[u8]> {
+        self.defaults.iter()
+            .find(|&&(s, f, _)| s == st && f == field)
+            .map(|&(_, _, ref v)| &v[compact as usize][..])
+    }
+
+    fn skip(&self, st: &str, field: &Symbol) -> Option<SkipFn> {
+        self.skips.iter()
+            .find(|&&(s, f, _)| s == st && field.matches(f))
+            .map(|&(_, _, skip)| skip)
+    }
+
+    fn has_skips(&self, st: &str) -> bool {
+        self.skips.iter().any(|&(s, _, _)| s == st)
+    }
+}
+
+/// Encode `value` on its own
+fn encode_value<T: Encodable>(value: &T,
+                              compact: bool) -> Result<Vec<u8>, Error> {
+    let mut encoded = Vec::new();
+
+    {
+        let mut encoder = Encoder {
+            writer: &mut encoded,
+            payload: None,
+            compact: compact,
+        };
+
+        try!(value.encode(&mut encoder));
+    }
+
+    Ok(encoded)
+}
 
+/// Decode a value of type `T` and throw it away
+fn skip_value<T: Decodable>(d: &mut Decoder) -> Result<(), Error> {
+    T::decode(d).map(|_| ())
+}
+
+/// Rustation savestate format serializer. The format predates serde
+/// and mirrors what `rustc-serialize`'s derived implementations used
+/// to produce, so that older savestates can still be loaded:
+///
+/// * structs are stored as their name followed by the name and value
+///   of each field. Tuple structs use `_field0`, `_field1`... as
+///   field names.
+/// * enums are stored as their name followed by the variant name and
+///   the index and value of each of the variant's fields.
+/// * sequences, tuples and maps are prefixed by their length, options
+///   by a bool.
+///
+/// The emulator core hasn't been migrated to serde yet, its state only
+/// implements `rustc-serialize`'s traits so the encoder implements both
+/// `rustc_serialize::Encoder` and `serde::Serializer`. `rustc-serialize`
+/// stores `usize` and `isize` as 32bit integers, serde as 64bit
+/// integers: `usize` and `isize` fields serialized with serde must use
+/// `#[serde(with = "savestate::usize32")]` or `savestate::isize32` to
+/// stay compatible.
 pub struct Encoder<'a> {
     writer: &'a mut io::Write,
+    /// Uncompressed payload if the savestate is compressed. It's
+    /// written to `writer` by `finish`.
+    payload: Option<Vec<u8>>,
+    /// If true symbols are encoded as tags
+    compact: bool,
 }
 
 impl<'a> Encoder<'a> {
-    pub fn new(writer: &'a mut io::Write) -> Result<Encoder<'a>, Error> {
+    pub fn new(writer: &'a mut io::Write,
+               header: &Header) -> Result<Encoder<'a>, Error> {
 
         let mut encoder =  Encoder {
-            writer: writer
+            writer: writer,
+            payload: None,
+            compact: false,
         };
 
         // Magic
         try!(encoder.write_bytes(MAGIC));
 
-        // It's pointless to store a version here since savestates
-        // will probably break every time we make a significant change
-        // to the core of the emulator.
+        // The header is always verbose, it's what tells the decoder
+        // which encoding is used for the rest of the savestate
+        try!(header.serialize(&mut encoder));
+
+        if header.compressed {
+            encoder.payload = Some(Vec::new());
+        }
+
+        encoder.compact = header.compact;
 
         Ok(encoder)
     }
 
-    fn write_bytes(&mut self, b: &[u8]) -> Result<(), Error> {
-        match self.writer.write_all(b) {
-            Ok(_) => Ok(()),
-            Err(e) => Err(Error::IoError(e)),
-        }
-    }
-}
+    /// Complete the savestate. Must be called once everything has
+    /// been encoded, otherwise compressed savestates are left empty.
+    pub fn finish(self) -> Result<(), Error> {
+        let payload =
+            match self.payload {
+                Some(p) => p,
+                None => return Ok(()),
+            };
 
-impl<'a> ::rustc_serialize::Encoder for Encoder<'a> {
+        let compressed =
+            ::miniz_oxide::deflate::compress_to_vec(&payload,
+                                                    COMPRESSION_LEVEL);
 
-    type Error = Error;
+        let mut encoder = Encoder {
+            writer: self.writer,
+            payload: None,
+            compact: false,
+        };
 
-    fn emit_nil(&mut self) -> Result<(), Error> {
-        self.emit_str("nil")
+        try!(encoder.emit_usize(compressed.len()));
+        encoder.write_bytes(&compressed)
+    }
+
+    /// Write a struct, field, enum or variant name
+    fn emit_symbol(&mut self, s: &str) -> Result<(), Error> {
+        if self.compact {
+            self.emit_u32(symbol_tag(s))
+        } else {
+            self.emit_str(s)
+        }
     }
 
     fn emit_usize(&mut self, v: usize) -> Result<(), Error> {
@@ -84,254 +302,1057 @@ impl<'a> ::rustc_serialize::Encoder for Encoder<'a> {
         self.write_bytes(&[v])
     }
 
-    fn emit_isize(&mut self, v: isize) -> Result<(), Error> {
-        if v as i32 as isize != v {
-            Err(Error::ISizeOverflow(v))
-        } else {
-            self.emit_i32(v as i32)
-        }
-    }
+    fn emit_bytes(&mut self, v: &[u8]) -> Result<(), Error> {
+        try!(self.emit_usize(v.len()));
 
-    fn emit_i64(&mut self, v: i64) -> Result<(), Error> {
-        self.emit_u64(v as u64)
+        self.write_bytes(v)
     }
 
-    fn emit_i32(&mut self, v: i32) -> Result<(), Error> {
-        self.emit_u32(v as u32)
-    }
+    fn emit_str(&mut self, v: &str) -> Result<(), Error> {
+        // Convert into bytes
+        let s = v.as_bytes();
 
-    fn emit_i16(&mut self, v: i16) -> Result<(), Error> {
-        self.emit_u16(v as u16)
-    }
+        let len = s.len();
 
-    fn emit_i8(&mut self, v: i8) -> Result<(), Error> {
-        self.emit_u8(v as u8)
-    }
+        if len > STRING_MAX_LEN {
+            return Err(Error::StringTooLong(len));
+        }
 
-    fn emit_bool(&mut self, v: bool) -> Result<(), Error> {
-        self.emit_u8(v as u8)
+        self.emit_bytes(s)
     }
 
-    fn emit_f64(&mut self, _: f64) -> Result<(), Error> {
-        panic!("f64 serialization")
+    fn write_bytes(&mut self, b: &[u8]) -> Result<(), Error> {
+        if let Some(ref mut payload) = self.payload {
+            payload.extend_from_slice(b);
+            return Ok(());
+        }
+
+        match self.writer.write_all(b) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(Error::IoError(e)),
+        }
     }
+}
+
+/// Return the maximum size of a savestate whose uncompressed size is
+/// `len`. Deflate falls back on stored blocks for incompressible
+/// data, each of them adding 5 bytes of overhead. A stored block can
+/// hold up to 64KB but miniz_oxide flushes smaller ones, so we budget
+/// 5 bytes per 16KB of data and also account for the payload length.
+/// `test_compression` checks that incompressible data fits.
+pub fn max_len(len: usize) -> usize {
+    len + (len / 0x4000 + 1) * 5 + 64
+}
+
+/// Return the tag used to represent symbol `s` in the compact
+/// encoding: its 32bit FNV-1a hash. Collisions are harmless as long
+/// as they don't happen between the fields of a given struct or the
+/// variants of a given enum.
+pub fn symbol_tag(s: &str) -> u32 {
+    s.bytes().fold(0x811c9dc5, |h, b| (h ^ b as u32).wrapping_mul(0x01000193))
+}
+
+/// Name of the `index`th field of a tuple struct
+fn tuple_field_name(index: usize) -> String {
+    format!("_field{}", index)
+}
+
+/// Serialize a `usize` as a 32bit integer the way `rustc-serialize`
+/// does. Use with `#[serde(with = "savestate::usize32")]`.
+pub mod usize32 {
+    use serde::{ser, Serialize, Serializer, Deserialize, Deserializer};
+
+    pub fn serialize<S>(v: &usize, s: S) -> Result<S::Ok, S::Error>
+        where S: Serializer {
+
+        let v = *v;
 
-    fn emit_f32(&mut self, _: f32) -> Result<(), Error> {
-        panic!("f32 serialization")
+        if v as u32 as usize != v {
+            let msg = format!("usize {} doesn't fit in 32bits", v);
+
+            return Err(ser::Error::custom(msg));
+        }
+
+        (v as u32).serialize(s)
     }
 
-    fn emit_char(&mut self, v: char) -> Result<(), Error> {
-        self.emit_u32(v as u32)
+    pub fn deserialize<'de, D>(d: D) -> Result<usize, D::Error>
+        where D: Deserializer<'de> {
+
+        u32::deserialize(d).map(|v| v as usize)
     }
+}
 
-    fn emit_str(&mut self, v: &str) -> Result<(), Error> {
-        // Convert into bytes
-        let s = v.as_bytes();
+/// Serialize an `isize` as a 32bit integer the way `rustc-serialize`
+/// does. Use with `#[serde(with = "savestate::isize32")]`.
+pub mod isize32 {
+    use serde::{ser, Serialize, Serializer, Deserialize, Deserializer};
 
-        let len = s.len();
+    pub fn serialize<S>(v: &isize, s: S) -> Result<S::Ok, S::Error>
+        where S: Serializer {
 
-        if len > STRING_MAX_LEN {
-            return Err(Error::StringTooLong(len));
-        }
+        let v = *v;
+
+        if v as i32 as isize != v {
+            let msg = format!("isize {} doesn't fit in 32bits", v);
 
-        try!(self.emit_usize(len));
-        try!(self.write_bytes(s));
+            return Err(ser::Error::custom(msg));
+        }
 
-        Ok(())
+        (v as i32).serialize(s)
     }
 
-    fn emit_enum<F>(&mut self, name: &str, f: F) -> Result<(), Error>
-        where F: FnOnce(&mut Self) -> Result<(), Error> {
-        try!(self.emit_str(name));
+    pub fn deserialize<'de, D>(d: D) -> Result<isize, D::Error>
+        where D: Deserializer<'de> {
 
-        f(self)
+        i32::deserialize(d).map(|v| v as isize)
     }
+}
 
-    fn emit_enum_variant<F>(&mut self,
-                            v_name: &str,
-                            _v_id: usize,
-                            _len: usize,
-                            f: F) -> Result<(), Error>
-        where F: FnOnce(&mut Self) -> Result<(), Error> {
+/// Symbol read from a savestate
+enum Symbol<'a> {
+    /// Symbol stored as a string in the verbose encoding
+    Name(&'a str),
+    /// Symbol stored as a tag in the compact encoding
+    Tag(u32),
+}
 
-        // We store the name instead of the ID in order not to end up
-        // with messed up state if an enum gets reordered or something
-        try!(self.emit_str(v_name));
+impl<'a> Symbol<'a> {
+    /// Return true if the symbol stands for `name`
+    fn matches(&self, name: &str) -> bool {
+        match *self {
+            Symbol::Name(n) => n == name,
+            Symbol::Tag(t) => t == symbol_tag(name),
+        }
+    }
 
-        f(self)
+    fn into_string(self) -> String {
+        match self {
+            Symbol::Name(n) => n.into(),
+            Symbol::Tag(t) => format!("#{:08x}", t),
+        }
     }
+}
 
-    fn emit_enum_variant_arg<F>(&mut self,
-                                a_idx: usize,
-                                f: F) -> Result<(), Error>
-        where F: FnOnce(&mut Self) -> Result<(), Error> {
-        try!(self.emit_usize(a_idx));
+impl<'a, 'b> ser::Serializer for &'b mut Encoder<'a> {
+    type Ok = ();
+    type Error = Error;
 
-        f(self)
+    type SerializeSeq = Compound<'b, 'a>;
+    type SerializeTuple = Compound<'b, 'a>;
+    type SerializeTupleStruct = Compound<'b, 'a>;
+    type SerializeTupleVariant = Compound<'b, 'a>;
+    type SerializeMap = Compound<'b, 'a>;
+    type SerializeStruct = Compound<'b, 'a>;
+    type SerializeStructVariant = Compound<'b, 'a>;
+
+    fn serialize_bool(self, v: bool) -> Result<(), Error> {
+        self.emit_u8(v as u8)
     }
 
-    fn emit_enum_struct_variant<F>(&mut self,
-                                   _v_name: &str,
-                                   _v_id: usize,
-                                   _len: usize,
-                                   _f: F) -> Result<(), Error>
-        where F: FnOnce(&mut Self) -> Result<(), Error> {
-        panic!()
+    fn serialize_i8(self, v: i8) -> Result<(), Error> {
+        self.emit_u8(v as u8)
     }
 
-    fn emit_enum_struct_variant_field<F>(&mut self,
-                                         _f_name: &str,
-                                         _f_idx: usize,
-                                         _f: F) -> Result<(), Error>
-        where F: FnOnce(&mut Self) -> Result<(), Error> {
-        panic!()
+    fn serialize_i16(self, v: i16) -> Result<(), Error> {
+        self.emit_u16(v as u16)
     }
 
-    fn emit_struct<F>(&mut self,
-                      name: &str,
-                      _: usize,
-                      f: F) -> Result<(), Error>
-        where F: FnOnce(&mut Self) -> Result<(), Error> {
+    fn serialize_i32(self, v: i32) -> Result<(), Error> {
+        self.emit_u32(v as u32)
+    }
 
-        try!(self.emit_str(name));
+    fn serialize_i64(self, v: i64) -> Result<(), Error> {
+        self.emit_u64(v as u64)
+    }
 
-        f(self)
+    fn serialize_u8(self, v: u8) -> Result<(), Error> {
+        self.emit_u8(v)
     }
 
-    fn emit_struct_field<F>(&mut self,
-                            f_name: &str,
-                            _: usize,
-                            f: F) -> Result<(), Error>
-        where F: FnOnce(&mut Self) -> Result<(), Error> {
+    fn serialize_u16(self, v: u16) -> Result<(), Error> {
+        self.emit_u16(v)
+    }
 
-        try!(self.emit_str(f_name));
+    fn serialize_u32(self, v: u32) -> Result<(), Error> {
+        self.emit_u32(v)
+    }
 
-        f(self)
+    fn serialize_u64(self, v: u64) -> Result<(), Error> {
+        self.emit_u64(v)
     }
 
-    fn emit_tuple<F>(&mut self, len: usize, f: F) -> Result<(), Error>
-        where F: FnOnce(&mut Self) -> Result<(), Error> {
+    fn serialize_f32(self, v: f32) -> Result<(), Error> {
+        self.emit_u32(v.to_bits())
+    }
 
-        self.emit_seq(len, f)
+    fn serialize_f64(self, v: f64) -> Result<(), Error> {
+        // Store the raw IEEE 754 representation to make sure we get
+        // the exact same value back
+        self.emit_u64(v.to_bits())
     }
 
-    fn emit_tuple_arg<F>(&mut self, idx: usize, f: F) -> Result<(), Error>
-        where F: FnOnce(&mut Self) -> Result<(), Error> {
+    fn serialize_char(self, v: char) -> Result<(), Error> {
+        self.emit_u32(v as u32)
+    }
 
-        self.emit_seq_elt(idx, f)
+    fn serialize_str(self, v: &str) -> Result<(), Error> {
+        self.emit_str(v)
     }
 
-    fn emit_tuple_struct<F>(&mut self, _name: &str, _len: usize, _f: F) -> Result<(), Error>
-        where F: FnOnce(&mut Self) -> Result<(), Error> {
-        panic!()
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), Error> {
+        // Same as a sequence of u8
+        self.emit_bytes(v)
     }
 
-    fn emit_tuple_struct_arg<F>(&mut self, _f_idx: usize, _f: F) -> Result<(), Error>
-        where F: FnOnce(&mut Self) -> Result<(), Error> {
-        panic!()
+    fn serialize_none(self) -> Result<(), Error> {
+        self.emit_u8(0)
     }
 
-    fn emit_option<F>(&mut self, f: F) -> Result<(), Error>
-        where F: FnOnce(&mut Self) -> Result<(), Error> {
+    fn serialize_some<T>(self, v: &T) -> Result<(), Error>
+        where T: ?Sized + Serialize {
 
-        f(self)
+        try!(self.emit_u8(1));
+
+        v.serialize(self)
     }
 
-    fn emit_option_none(&mut self) -> Result<(), Error> {
-        self.emit_bool(false)
+    fn serialize_unit(self) -> Result<(), Error> {
+        self.emit_symbol("nil")
     }
 
-    fn emit_option_some<F>(&mut self, f: F) -> Result<(), Error>
-        where F: FnOnce(&mut Self) -> Result<(), Error> {
+    fn serialize_unit_struct(self, name: &'static str) -> Result<(), Error> {
+        self.emit_symbol(name)
+    }
 
-        try!(self.emit_bool(true));
+    fn serialize_unit_variant(self,
+                              name: &'static str,
+                              _: u32,
+                              variant: &'static str) -> Result<(), Error> {
+        try!(self.emit_symbol(name));
 
-        f(self)
+        // We store the name instead of the ID in order not to end up
+        // with messed up state if an enum gets reordered or something
+        self.emit_symbol(variant)
     }
 
-    fn emit_seq<F>(&mut self, len: usize, f: F) -> Result<(), Error>
-        where F: FnOnce(&mut Self) -> Result<(), Error> {
+    fn serialize_newtype_struct<T>(self,
+                                   name: &'static str,
+                                   v: &T) -> Result<(), Error>
+        where T: ?Sized + Serialize {
 
-        try!(self.emit_usize(len));
+        try!(self.emit_symbol(name));
+        try!(self.emit_symbol(&tuple_field_name(0)));
 
-        f(self)
+        v.serialize(self)
     }
 
-    fn emit_seq_elt<F>(&mut self, _: usize, f: F) -> Result<(), Error>
-        where F: FnOnce(&mut Self) -> Result<(), Error> {
+    fn serialize_newtype_variant<T>(self,
+                                    name: &'static str,
+                                    _: u32,
+                                    variant: &'static str,
+                                    v: &T) -> Result<(), Error>
+        where T: ?Sized + Serialize {
 
-        f(self)
+        try!(self.emit_symbol(name));
+        try!(self.emit_symbol(variant));
+        try!(self.emit_usize(0));
+
+        v.serialize(self)
     }
 
-    fn emit_map<F>(&mut self, len: usize, f: F) -> Result<(), Error>
-        where F: FnOnce(&mut Self) -> Result<(), Error> {
+    fn serialize_seq(self,
+                     len: Option<usize>) -> Result<Compound<'b, 'a>, Error> {
+        match len {
+            Some(len) => {
+                try!(self.emit_usize(len));
 
-        try!(self.emit_usize(len));
+                Ok(Compound::new(self))
+            }
+            None => Err(Error::UnknownLength),
+        }
+    }
 
-        f(self)
+    fn serialize_tuple(self, len: usize) -> Result<Compound<'b, 'a>, Error> {
+        self.serialize_seq(Some(len))
     }
 
-    fn emit_map_elt_key<F>(&mut self, _idx: usize, _f: F) -> Result<(), Error>
-        where F: FnOnce(&mut Self) -> Result<(), Error> {
-        panic!()
+    fn serialize_tuple_struct(self,
+                              name: &'static str,
+                              _: usize) -> Result<Compound<'b, 'a>, Error> {
+        try!(self.emit_symbol(name));
+
+        Ok(Compound::new(self))
     }
 
-    fn emit_map_elt_val<F>(&mut self, _idx: usize, _f: F) -> Result<(), Error>
-        where F: FnOnce(&mut Self) -> Result<(), Error> {
-        panic!()
+    fn serialize_tuple_variant(self,
+                               name: &'static str,
+                               _: u32,
+                               variant: &'static str,
+                               _: usize) -> Result<Compound<'b, 'a>, Error> {
+        try!(self.emit_symbol(name));
+        try!(self.emit_symbol(variant));
+
+        Ok(Compound::new(self))
     }
-}
 
-/// Rustation savestate format deserializer
-pub struct Decoder<'a> {
-    reader: &'a mut io::Read,
-}
+    fn serialize_map(self,
+                     len: Option<usize>) -> Result<Compound<'b, 'a>, Error> {
+        self.serialize_seq(len)
+    }
 
-impl<'a> Decoder<'a> {
-    pub fn new(reader: &'a mut io::Read) -> Result<Decoder<'a>, Error> {
+    fn serialize_struct(self,
+                        name: &'static str,
+                        _: usize) -> Result<Compound<'b, 'a>, Error> {
+        try!(self.emit_symbol(name));
 
-        let mut decoder = Decoder {
-            reader: reader,
-        };
+        Ok(Compound::new(self))
+    }
 
-        // Check that the magic is valid
-        let mut magic = [0; 4];
+    fn serialize_struct_variant(self,
+                                name: &'static str,
+                                _: u32,
+                                variant: &'static str,
+                                _: usize) -> Result<Compound<'b, 'a>, Error> {
+        // The fields are stored like tuple variant fields, without
+        // their names
+        self.serialize_tuple_variant(name, 0, variant, 0)
+    }
 
-        try!(decoder.read_bytes(&mut magic));
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
 
-        if magic != MAGIC {
-            Err(Error::BadMagic)
-        } else {
-            Ok(decoder)
+/// Serializer for the elements of a compound type
+pub struct Compound<'b, 'a: 'b> {
+    encoder: &'b mut Encoder<'a>,
+    /// Index of the next element
+    index: usize,
+}
+
+impl<'b, 'a> Compound<'b, 'a> {
+    fn new(encoder: &'b mut Encoder<'a>) -> Compound<'b, 'a> {
+        Compound {
+            encoder: encoder,
+            index: 0,
         }
     }
 
-    fn read_bytes(&mut self, b: &mut [u8]) -> Result<(), Error> {
-        match self.reader.read_exact(b) {
-            Ok(_) => Ok(()),
-            Err(e) => Err(Error::IoError(e)),
-        }
+    /// Serialize the next field of a tuple struct
+    fn tuple_field<T>(&mut self, v: &T) -> Result<(), Error>
+        where T: ?Sized + Serialize {
+
+        try!(self.encoder.emit_symbol(&tuple_field_name(self.index)));
+        self.index += 1;
+
+        v.serialize(&mut *self.encoder)
     }
 
-    /// Validate that an expected symbol matches the file value
-    fn validate_symbol(&mut self, expected: &str) -> Result<(), Error> {
-        use rustc_serialize::Decoder;
+    /// Serialize the next field of an enum variant
+    fn variant_field<T>(&mut self, v: &T) -> Result<(), Error>
+        where T: ?Sized + Serialize {
 
-        let s = try!(self.read_str());
+        try!(self.encoder.emit_usize(self.index));
+        self.index += 1;
 
-        if s != expected {
-            Err(Error::BadSymbol(expected.into(), s))
-        } else {
-            Ok(())
-        }
+        v.serialize(&mut *self.encoder)
     }
 }
 
-impl<'a> ::rustc_serialize::Decoder for Decoder<'a> {
+impl<'b, 'a> ser::SerializeSeq for Compound<'b, 'a> {
+    type Ok = ();
     type Error = Error;
 
-    fn read_nil(&mut self) -> Result<(), Error> {
-        self.validate_symbol("nil")
+    fn serialize_element<T>(&mut self, v: &T) -> Result<(), Error>
+        where T: ?Sized + Serialize {
+
+        v.serialize(&mut *self.encoder)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl<'b, 'a> ser::SerializeTuple for Compound<'b, 'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T>(&mut self, v: &T) -> Result<(), Error>
+        where T: ?Sized + Serialize {
+
+        v.serialize(&mut *self.encoder)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl<'b, 'a> ser::SerializeTupleStruct for Compound<'b, 'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self, v: &T) -> Result<(), Error>
+        where T: ?Sized + Serialize {
+
+        self.tuple_field(v)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl<'b, 'a> ser::SerializeTupleVariant for Compound<'b, 'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self, v: &T) -> Result<(), Error>
+        where T: ?Sized + Serialize {
+
+        self.variant_field(v)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl<'b, 'a> ser::SerializeMap for Compound<'b, 'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_key<T>(&mut self, k: &T) -> Result<(), Error>
+        where T: ?Sized + Serialize {
+
+        k.serialize(&mut *self.encoder)
+    }
+
+    fn serialize_value<T>(&mut self, v: &T) -> Result<(), Error>
+        where T: ?Sized + Serialize {
+
+        v.serialize(&mut *self.encoder)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl<'b, 'a> ser::SerializeStruct for Compound<'b, 'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self,
+                          name: &'static str,
+                          v: &T) -> Result<(), Error>
+        where T: ?Sized + Serialize {
+
+        try!(self.encoder.emit_symbol(name));
+
+        v.serialize(&mut *self.encoder)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl<'b, 'a> ser::SerializeStructVariant for Compound<'b, 'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self,
+                          _: &'static str,
+                          v: &T) -> Result<(), Error>
+        where T: ?Sized + Serialize {
+
+        self.variant_field(v)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl<'a> ::rustc_serialize::Encoder for Encoder<'a> {
+    type Error = Error;
+
+    fn emit_nil(&mut self) -> Result<(), Error> {
+        self.emit_symbol("nil")
+    }
+
+    fn emit_usize(&mut self, v: usize) -> Result<(), Error> {
+        Encoder::emit_usize(self, v)
+    }
+
+    fn emit_u64(&mut self, v: u64) -> Result<(), Error> {
+        Encoder::emit_u64(self, v)
+    }
+
+    fn emit_u32(&mut self, v: u32) -> Result<(), Error> {
+        Encoder::emit_u32(self, v)
+    }
+
+    fn emit_u16(&mut self, v: u16) -> Result<(), Error> {
+        Encoder::emit_u16(self, v)
+    }
+
+    fn emit_u8(&mut self, v: u8) -> Result<(), Error> {
+        Encoder::emit_u8(self, v)
+    }
+
+    fn emit_isize(&mut self, v: isize) -> Result<(), Error> {
+        if v as i32 as isize != v {
+            Err(Error::ISizeOverflow(v))
+        } else {
+            self.emit_i32(v as i32)
+        }
+    }
+
+    fn emit_i64(&mut self, v: i64) -> Result<(), Error> {
+        Encoder::emit_u64(self, v as u64)
+    }
+
+    fn emit_i32(&mut self, v: i32) -> Result<(), Error> {
+        Encoder::emit_u32(self, v as u32)
+    }
+
+    fn emit_i16(&mut self, v: i16) -> Result<(), Error> {
+        Encoder::emit_u16(self, v as u16)
+    }
+
+    fn emit_i8(&mut self, v: i8) -> Result<(), Error> {
+        Encoder::emit_u8(self, v as u8)
+    }
+
+    fn emit_bool(&mut self, v: bool) -> Result<(), Error> {
+        Encoder::emit_u8(self, v as u8)
+    }
+
+    fn emit_f64(&mut self, v: f64) -> Result<(), Error> {
+        // Store the raw IEEE 754 representation to make sure we get
+        // the exact same value back
+        Encoder::emit_u64(self, v.to_bits())
+    }
+
+    fn emit_f32(&mut self, v: f32) -> Result<(), Error> {
+        Encoder::emit_u32(self, v.to_bits())
+    }
+
+    fn emit_char(&mut self, v: char) -> Result<(), Error> {
+        Encoder::emit_u32(self, v as u32)
+    }
+
+    fn emit_str(&mut self, v: &str) -> Result<(), Error> {
+        Encoder::emit_str(self, v)
+    }
+
+    fn emit_enum<F>(&mut self, name: &str, f: F) -> Result<(), Error>
+        where F: FnOnce(&mut Self) -> Result<(), Error> {
+        try!(self.emit_symbol(name));
+
+        f(self)
+    }
+
+    fn emit_enum_variant<F>(&mut self,
+                            v_name: &str,
+                            _v_id: usize,
+                            _len: usize,
+                            f: F) -> Result<(), Error>
+        where F: FnOnce(&mut Self) -> Result<(), Error> {
+
+        // We store the name instead of the ID in order not to end up
+        // with messed up state if an enum gets reordered or something
+        try!(self.emit_symbol(v_name));
+
+        f(self)
+    }
+
+    fn emit_enum_variant_arg<F>(&mut self,
+                                a_idx: usize,
+                                f: F) -> Result<(), Error>
+        where F: FnOnce(&mut Self) -> Result<(), Error> {
+        try!(Encoder::emit_usize(self, a_idx));
+
+        f(self)
+    }
+
+    fn emit_enum_struct_variant<F>(&mut self,
+                                   v_name: &str,
+                                   v_id: usize,
+                                   len: usize,
+                                   f: F) -> Result<(), Error>
+        where F: FnOnce(&mut Self) -> Result<(), Error> {
+
+        self.emit_enum_variant(v_name, v_id, len, f)
+    }
+
+    fn emit_enum_struct_variant_field<F>(&mut self,
+                                         _f_name: &str,
+                                         f_idx: usize,
+                                         f: F) -> Result<(), Error>
+        where F: FnOnce(&mut Self) -> Result<(), Error> {
+
+        // The derived implementations encode struct variants like
+        // tuple variants and so does serde, the fields are stored by
+        // index
+        self.emit_enum_variant_arg(f_idx, f)
+    }
+
+    fn emit_struct<F>(&mut self,
+                      name: &str,
+                      _: usize,
+                      f: F) -> Result<(), Error>
+        where F: FnOnce(&mut Self) -> Result<(), Error> {
+
+        try!(self.emit_symbol(name));
+
+        f(self)
+    }
+
+    fn emit_struct_field<F>(&mut self,
+                            f_name: &str,
+                            _: usize,
+                            f: F) -> Result<(), Error>
+        where F: FnOnce(&mut Self) -> Result<(), Error> {
+
+        try!(self.emit_symbol(f_name));
+
+        f(self)
+    }
+
+    fn emit_tuple<F>(&mut self, len: usize, f: F) -> Result<(), Error>
+        where F: FnOnce(&mut Self) -> Result<(), Error> {
+
+        self.emit_seq(len, f)
+    }
+
+    fn emit_tuple_arg<F>(&mut self, idx: usize, f: F) -> Result<(), Error>
+        where F: FnOnce(&mut Self) -> Result<(), Error> {
+
+        self.emit_seq_elt(idx, f)
+    }
+
+    fn emit_tuple_struct<F>(&mut self,
+                            name: &str,
+                            len: usize,
+                            f: F) -> Result<(), Error>
+        where F: FnOnce(&mut Self) -> Result<(), Error> {
+
+        try!(self.emit_symbol(name));
+
+        self.emit_tuple(len, f)
+    }
+
+    fn emit_tuple_struct_arg<F>(&mut self,
+                                f_idx: usize,
+                                f: F) -> Result<(), Error>
+        where F: FnOnce(&mut Self) -> Result<(), Error> {
+
+        self.emit_tuple_arg(f_idx, f)
+    }
+
+    fn emit_option<F>(&mut self, f: F) -> Result<(), Error>
+        where F: FnOnce(&mut Self) -> Result<(), Error> {
+
+        f(self)
+    }
+
+    fn emit_option_none(&mut self) -> Result<(), Error> {
+        Encoder::emit_u8(self, 0)
+    }
+
+    fn emit_option_some<F>(&mut self, f: F) -> Result<(), Error>
+        where F: FnOnce(&mut Self) -> Result<(), Error> {
+
+        try!(Encoder::emit_u8(self, 1));
+
+        f(self)
+    }
+
+    fn emit_seq<F>(&mut self, len: usize, f: F) -> Result<(), Error>
+        where F: FnOnce(&mut Self) -> Result<(), Error> {
+
+        try!(Encoder::emit_usize(self, len));
+
+        f(self)
+    }
+
+    fn emit_seq_elt<F>(&mut self, _: usize, f: F) -> Result<(), Error>
+        where F: FnOnce(&mut Self) -> Result<(), Error> {
+
+        f(self)
+    }
+
+    fn emit_map<F>(&mut self, len: usize, f: F) -> Result<(), Error>
+        where F: FnOnce(&mut Self) -> Result<(), Error> {
+
+        try!(Encoder::emit_usize(self, len));
+
+        f(self)
+    }
+
+    fn emit_map_elt_key<F>(&mut self, _idx: usize, f: F) -> Result<(), Error>
+        where F: FnOnce(&mut Self) -> Result<(), Error> {
+
+        f(self)
+    }
+
+    fn emit_map_elt_val<F>(&mut self, _idx: usize, f: F) -> Result<(), Error>
+        where F: FnOnce(&mut Self) -> Result<(), Error> {
+
+        f(self)
+    }
+}
+
+/// Rustation savestate format deserializer
+pub struct Decoder<'a> {
+    reader: &'a mut io::Read,
+    /// Decompressed payload if the savestate is compressed, read
+    /// instead of `reader`
+    payload: Option<io::Cursor<Vec<u8>>>,
+    /// Savestate header
+    header: Header,
+    /// Bytes to be read before resuming reading from `reader`. Used
+    /// to "unread" symbols and to inject default values.
+    pushback: Vec<u8>,
+    /// Names of the structs being decoded by `rustc_serialize::Decoder`
+    structs: Vec<String>,
+    /// Migrations applied while decoding
+    migrations: Migrations,
+    /// If true symbols are decoded as tags
+    compact: bool,
+    /// Buffer reused to hold the raw bytes of the symbols, see
+    /// `take_scratch`
+    scratch: Vec<u8>,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(reader: &'a mut io::Read) -> Result<Decoder<'a>, Error> {
+
+        let mut decoder = Decoder {
+            reader: reader,
+            payload: None,
+            header: Header::legacy(),
+            pushback: Vec::new(),
+            structs: Vec::new(),
+            migrations: Migrations::new(),
+            compact: false,
+            scratch: Vec::new(),
+        };
+
+        // Check that the magic is valid
+        let mut magic = [0; 4];
+
+        try!(decoder.read_bytes(&mut magic));
+
+        if magic != MAGIC {
+            return Err(Error::BadMagic);
+        }
+
+        // Savestates created before the header was introduced start
+        // directly with the emulator state
+        let mut raw = Vec::new();
+
+        let has_header = try!(decoder.read_symbol(&mut raw)).matches("Header");
+
+        decoder.unread(&raw);
+
+        if has_header {
+            decoder.header = try!(decoder.read_header());
+        }
+
+        if decoder.header.version > VERSION {
+            return Err(Error::UnsupportedVersion(decoder.header.version));
+        }
+
+        if decoder.header.compressed {
+            try!(decoder.decompress());
+        }
+
+        decoder.compact = decoder.header.compact;
+
+        Ok(decoder)
+    }
+
+    /// Return the savestate header
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// Set the migrations to be applied while decoding
+    pub fn set_migrations(&mut self, migrations: Migrations) {
+        self.migrations = migrations;
+    }
+
+    /// Return the rest of the savestate without decoding it,
+    /// decompressed if needed. Used to dump savestates whose contents
+    /// we can't decode.
+    pub fn read_to_end(&mut self) -> Result<Vec<u8>, Error> {
+        let mut rest = ::std::mem::replace(&mut self.pushback, Vec::new());
+
+        let res =
+            match self.payload {
+                Some(ref mut p) => p.read_to_end(&mut rest),
+                None => self.reader.read_to_end(&mut rest),
+            };
+
+        match res {
+            Ok(_) => Ok(rest),
+            Err(e) => Err(Error::IoError(e)),
+        }
+    }
+
+    /// Read the savestate header. The fields depend on the version so
+    /// it doesn't implement `Deserialize`.
+    fn read_header(&mut self) -> Result<Header, Error> {
+        try!(self.validate_symbol("Header"));
+
+        let version: u32 = try!(self.read_field("version"));
+        let core_version = try!(self.read_field("core_version"));
+        let bios_hash = try!(self.read_field("bios_hash"));
+
+        // The compression flag was introduced in version 3
+        let compressed =
+            if version >= 3 {
+                try!(self.read_field("compressed"))
+            } else {
+                false
+            };
+
+        // The compact encoding was introduced in version 4
+        let compact =
+            if version >= 4 {
+                try!(self.read_field("compact"))
+            } else {
+                false
+            };
+
+        // The card hash was introduced in version 7
+        let card_hash =
+            if version >= 7 {
+                try!(self.read_field("card_hash"))
+            } else {
+                String::new()
+            };
+
+        Ok(Header {
+            version: version,
+            core_version: core_version,
+            bios_hash: bios_hash,
+            card_hash: card_hash,
+            compressed: compressed,
+            compact: compact,
+        })
+    }
+
+    /// Read the struct field `name` and return its value
+    fn read_field<T: DeserializeOwned>(&mut self,
+                                       name: &str) -> Result<T, Error> {
+        try!(self.validate_symbol(name));
+
+        T::deserialize(self)
+    }
+
+    /// Read and decompress the payload following the header
+    fn decompress(&mut self) -> Result<(), Error> {
+        let len = try!(self.read_usize());
+
+        if len > PAYLOAD_MAX_LEN {
+            return Err(Error::PayloadTooLong(len));
+        }
+
+        let mut compressed = vec![0; len];
+
+        try!(self.read_bytes(&mut compressed));
+
+        let payload =
+            ::miniz_oxide::inflate::decompress_to_vec_with_limit(
+                &compressed,
+                PAYLOAD_MAX_LEN);
+
+        match payload {
+            Ok(p) => {
+                self.payload = Some(io::Cursor::new(p));
+                Ok(())
+            }
+            Err(e) => Err(Error::BadPayload(format!("{:?}", e))),
+        }
+    }
+
+    fn read_bytes(&mut self, b: &mut [u8]) -> Result<(), Error> {
+        let n = ::std::cmp::min(b.len(), self.pushback.len());
+
+        b[..n].copy_from_slice(&self.pushback[..n]);
+        self.pushback.drain(..n);
+
+        let res =
+            match self.payload {
+                Some(ref mut p) => p.read_exact(&mut b[n..]),
+                None => self.reader.read_exact(&mut b[n..]),
+            };
+
+        match res {
+            Ok(_) => Ok(()),
+            Err(e) => Err(Error::IoError(e)),
+        }
+    }
+
+    /// Push `b` back in front of the input
+    fn unread(&mut self, b: &[u8]) {
+        self.pushback.splice(..0, b.iter().cloned());
+    }
+
+    /// Take the scratch buffer, emptied. It should be given back with
+    /// `put_scratch` once done so that we don't allocate a new buffer
+    /// for every symbol. Nested structs just end up using a new one.
+    fn take_scratch(&mut self) -> Vec<u8> {
+        let mut scratch = ::std::mem::replace(&mut self.scratch, Vec::new());
+
+        scratch.clear();
+
+        scratch
+    }
+
+    fn put_scratch(&mut self, scratch: Vec<u8>) {
+        self.scratch = scratch;
+    }
+
+    /// Read a symbol, appending the raw bytes read to `raw` so that
+    /// they can be pushed back if needed
+    fn read_symbol<'r>(&mut self,
+                       raw: &'r mut Vec<u8>) -> Result<Symbol<'r>, Error> {
+        let mut len = [0; 4];
+
+        try!(self.read_bytes(&mut len));
+        raw.extend_from_slice(&len);
+
+        let len = len.iter().rev().fold(0, |l, &b| (l << 8) | b as usize);
+
+        if self.compact {
+            // That was the tag
+            return Ok(Symbol::Tag(len as u32));
+        }
+
+        if len > STRING_MAX_LEN {
+            return Err(Error::StringTooLong(len));
+        }
+
+        let start = raw.len();
+
+        raw.resize(start + len, 0);
+        try!(self.read_bytes(&mut raw[start..]));
+
+        let name = &raw[start..];
+
+        match ::std::str::from_utf8(name) {
+            Ok(n) => Ok(Symbol::Name(n)),
+            // Build the owned error for `BadString`
+            Err(_) => Err(Error::BadString(String::from_utf8(name.to_vec())
+                                           .unwrap_err())),
+        }
+    }
+
+    /// Read the symbol of the field `f_name` of struct `st`, applying
+    /// the migrations if it doesn't match
+    fn read_field_symbol(&mut self,
+                         st: &str,
+                         f_name: &str) -> Result<(), Error> {
+        loop {
+            let mut raw = self.take_scratch();
+
+            // `Ok(None)` if we found the field, `Ok(Some(skip))` if
+            // it's a field removed since the savestate was created
+            let found =
+                match self.read_symbol(&mut raw) {
+                    Ok(ref s) if s.matches(f_name) => Ok(None),
+                    Ok(s) =>
+                        match self.migrations.skip(st, &s) {
+                            Some(skip) => Ok(Some(skip)),
+                            None => Err(Error::BadSymbol(f_name.into(),
+                                                         s.into_string())),
+                        },
+                    Err(e) => Err(e),
+                };
+
+            let found =
+                match found {
+                    Err(e) => {
+                        let compact = self.compact;
+
+                        // Field added since the savestate was created.
+                        // What we just read belongs to whatever comes
+                        // next so we put it back after the default
+                        // value.
+                        match self.migrations.default(st, f_name, compact) {
+                            Some(default) => {
+                                let pushback = &mut self.pushback;
+
+                                pushback.splice(..0, raw.iter().cloned());
+                                pushback.splice(..0, default.iter().cloned());
+
+                                Ok(None)
+                            }
+                            None => Err(e),
+                        }
+                    }
+                    found => found,
+                };
+
+            self.put_scratch(raw);
+
+            match try!(found) {
+                Some(skip) => try!(skip(self)),
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// Skip the fields at the end of struct `st` that have been
+    /// removed
+    fn skip_trailing_fields(&mut self, st: &str) -> Result<(), Error> {
+        loop {
+            let mut raw = self.take_scratch();
+
+            // We might be reading past the end of the struct here so
+            // we can't really trust what we get
+            let skip =
+                match self.read_symbol(&mut raw) {
+                    Ok(symbol) => self.migrations.skip(st, &symbol),
+                    Err(_) => None,
+                };
+
+            if skip.is_none() {
+                self.unread(&raw);
+            }
+
+            self.put_scratch(raw);
+
+            match skip {
+                Some(skip) => try!(skip(self)),
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// Validate that an expected symbol matches the file value
+    fn validate_symbol(&mut self, expected: &str) -> Result<(), Error> {
+        let mut raw = self.take_scratch();
+
+        let res =
+            match self.read_symbol(&mut raw) {
+                Ok(ref s) if s.matches(expected) => Ok(()),
+                Ok(s) =>
+                    Err(Error::BadSymbol(expected.into(), s.into_string())),
+                Err(e) => Err(e),
+            };
+
+        self.put_scratch(raw);
+
+        res
     }
 
     fn read_usize(&mut self) -> Result<usize, Error> {
@@ -372,83 +1393,563 @@ impl<'a> ::rustc_serialize::Decoder for Decoder<'a> {
     fn read_u16(&mut self) -> Result<u16, Error> {
         let mut b = [0; 2];
 
-        try!(self.read_bytes(&mut b));
+        try!(self.read_bytes(&mut b));
+
+        let mut v = 0;
+
+        for &b in b.iter().rev() {
+            v <<= 8;
+            v |= b as u16;
+        }
+
+        Ok(v)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, Error> {
+        let mut b = [0];
+
+        try!(self.read_bytes(&mut b));
+
+        Ok(b[0])
+    }
+
+    fn read_bool(&mut self) -> Result<bool, Error> {
+        match try!(self.read_u8()) {
+            0 => Ok(false),
+            1 => Ok(true),
+            n => Err(Error::BadBool(n)),
+        }
+    }
+
+    fn read_byte_buf(&mut self) -> Result<Vec<u8>, Error> {
+        // First read the length
+        let len = try!(self.read_usize());
+
+        if len > STRING_MAX_LEN {
+            return Err(Error::StringTooLong(len));
+        }
+
+        let mut buf = vec![0; len];
+
+        // Now we can read the contents
+        try!(self.read_bytes(&mut buf));
+
+        Ok(buf)
+    }
+
+    fn read_str(&mut self) -> Result<String, Error> {
+        let buf = try!(self.read_byte_buf());
+
+        String::from_utf8(buf).map_err(|e| Error::BadString(e))
+    }
+}
+
+impl<'de, 'a, 'b> de::Deserializer<'de> for &'b mut Decoder<'a> {
+    type Error = Error;
+
+    fn deserialize_any<V>(self, _: V) -> Result<V::Value, Error>
+        where V: Visitor<'de> {
+        // The format isn't self-describing
+        Err(Error::Unsupported("deserialize_any"))
+    }
+
+    fn deserialize_bool<V>(self, visitor: V) -> Result<V::Value, Error>
+        where V: Visitor<'de> {
+        visitor.visit_bool(try!(self.read_bool()))
+    }
+
+    fn deserialize_i8<V>(self, visitor: V) -> Result<V::Value, Error>
+        where V: Visitor<'de> {
+        visitor.visit_i8(try!(self.read_u8()) as i8)
+    }
+
+    fn deserialize_i16<V>(self, visitor: V) -> Result<V::Value, Error>
+        where V: Visitor<'de> {
+        visitor.visit_i16(try!(self.read_u16()) as i16)
+    }
+
+    fn deserialize_i32<V>(self, visitor: V) -> Result<V::Value, Error>
+        where V: Visitor<'de> {
+        visitor.visit_i32(try!(self.read_u32()) as i32)
+    }
+
+    fn deserialize_i64<V>(self, visitor: V) -> Result<V::Value, Error>
+        where V: Visitor<'de> {
+        visitor.visit_i64(try!(self.read_u64()) as i64)
+    }
+
+    fn deserialize_u8<V>(self, visitor: V) -> Result<V::Value, Error>
+        where V: Visitor<'de> {
+        visitor.visit_u8(try!(self.read_u8()))
+    }
+
+    fn deserialize_u16<V>(self, visitor: V) -> Result<V::Value, Error>
+        where V: Visitor<'de> {
+        visitor.visit_u16(try!(self.read_u16()))
+    }
+
+    fn deserialize_u32<V>(self, visitor: V) -> Result<V::Value, Error>
+        where V: Visitor<'de> {
+        visitor.visit_u32(try!(self.read_u32()))
+    }
+
+    fn deserialize_u64<V>(self, visitor: V) -> Result<V::Value, Error>
+        where V: Visitor<'de> {
+        visitor.visit_u64(try!(self.read_u64()))
+    }
+
+    fn deserialize_f32<V>(self, visitor: V) -> Result<V::Value, Error>
+        where V: Visitor<'de> {
+        visitor.visit_f32(f32::from_bits(try!(self.read_u32())))
+    }
+
+    fn deserialize_f64<V>(self, visitor: V) -> Result<V::Value, Error>
+        where V: Visitor<'de> {
+        visitor.visit_f64(f64::from_bits(try!(self.read_u64())))
+    }
+
+    fn deserialize_char<V>(self, visitor: V) -> Result<V::Value, Error>
+        where V: Visitor<'de> {
+        let c = try!(self.read_u32());
+
+        match ::std::char::from_u32(c) {
+            Some(c) => visitor.visit_char(c),
+            None => Err(Error::BadChar(c)),
+        }
+    }
+
+    fn deserialize_str<V>(self, visitor: V) -> Result<V::Value, Error>
+        where V: Visitor<'de> {
+        visitor.visit_string(try!(self.read_str()))
+    }
+
+    fn deserialize_string<V>(self, visitor: V) -> Result<V::Value, Error>
+        where V: Visitor<'de> {
+        visitor.visit_string(try!(self.read_str()))
+    }
+
+    fn deserialize_bytes<V>(self, visitor: V) -> Result<V::Value, Error>
+        where V: Visitor<'de> {
+        visitor.visit_byte_buf(try!(self.read_byte_buf()))
+    }
+
+    fn deserialize_byte_buf<V>(self, visitor: V) -> Result<V::Value, Error>
+        where V: Visitor<'de> {
+        visitor.visit_byte_buf(try!(self.read_byte_buf()))
+    }
+
+    fn deserialize_option<V>(self, visitor: V) -> Result<V::Value, Error>
+        where V: Visitor<'de> {
+        if try!(self.read_bool()) {
+            visitor.visit_some(self)
+        } else {
+            visitor.visit_none()
+        }
+    }
+
+    fn deserialize_unit<V>(self, visitor: V) -> Result<V::Value, Error>
+        where V: Visitor<'de> {
+        try!(self.validate_symbol("nil"));
+
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V>(self,
+                                  name: &'static str,
+                                  visitor: V) -> Result<V::Value, Error>
+        where V: Visitor<'de> {
+        try!(self.validate_symbol(name));
+
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V>(self,
+                                     name: &'static str,
+                                     visitor: V) -> Result<V::Value, Error>
+        where V: Visitor<'de> {
+        try!(self.validate_symbol(name));
+        try!(self.validate_symbol(&tuple_field_name(0)));
+
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V>(self, visitor: V) -> Result<V::Value, Error>
+        where V: Visitor<'de> {
+        let len = try!(self.read_usize());
+
+        visitor.visit_seq(Elements::new(self, len, ElementKind::Plain))
+    }
+
+    fn deserialize_tuple<V>(self,
+                            len: usize,
+                            visitor: V) -> Result<V::Value, Error>
+        where V: Visitor<'de> {
+        let l = try!(self.read_usize());
+
+        if l != len {
+            return Err(Error::BadTupleLength(len, l));
+        }
+
+        visitor.visit_seq(Elements::new(self, len, ElementKind::Plain))
+    }
+
+    fn deserialize_tuple_struct<V>(self,
+                                   name: &'static str,
+                                   len: usize,
+                                   visitor: V) -> Result<V::Value, Error>
+        where V: Visitor<'de> {
+        try!(self.validate_symbol(name));
+
+        visitor.visit_seq(Elements::new(self, len, ElementKind::TupleField))
+    }
+
+    fn deserialize_map<V>(self, visitor: V) -> Result<V::Value, Error>
+        where V: Visitor<'de> {
+        let len = try!(self.read_usize());
+
+        visitor.visit_map(Entries {
+            decoder: self,
+            remaining: len,
+        })
+    }
+
+    fn deserialize_struct<V>(self,
+                             name: &'static str,
+                             fields: &'static [&'static str],
+                             visitor: V) -> Result<V::Value, Error>
+        where V: Visitor<'de> {
+        try!(self.validate_symbol(name));
+
+        visitor.visit_map(Fields {
+            decoder: self,
+            st: name,
+            fields: fields,
+            index: 0,
+        })
+    }
+
+    fn deserialize_enum<V>(self,
+                           name: &'static str,
+                           variants: &'static [&'static str],
+                           visitor: V) -> Result<V::Value, Error>
+        where V: Visitor<'de> {
+        try!(self.validate_symbol(name));
+
+        visitor.visit_enum(Variant {
+            decoder: self,
+            variants: variants,
+        })
+    }
+
+    fn deserialize_identifier<V>(self, _: V) -> Result<V::Value, Error>
+        where V: Visitor<'de> {
+        // Identifiers are handled by `Fields` and `Variant`
+        Err(Error::Unsupported("deserialize_identifier"))
+    }
+
+    fn deserialize_ignored_any<V>(self, _: V) -> Result<V::Value, Error>
+        where V: Visitor<'de> {
+        Err(Error::Unsupported("deserialize_ignored_any"))
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
+
+/// What precedes each element of a sequence
+enum ElementKind {
+    /// Nothing (sequences and tuples)
+    Plain,
+    /// The field name (tuple structs)
+    TupleField,
+    /// The field index (enum variants)
+    VariantField,
+}
+
+/// Deserializer for the elements of sequences, tuples, tuple structs
+/// and enum variants
+struct Elements<'b, 'a: 'b> {
+    decoder: &'b mut Decoder<'a>,
+    /// Number of elements
+    len: usize,
+    /// Index of the next element
+    index: usize,
+    kind: ElementKind,
+}
+
+impl<'b, 'a> Elements<'b, 'a> {
+    fn new(decoder: &'b mut Decoder<'a>,
+           len: usize,
+           kind: ElementKind) -> Elements<'b, 'a> {
+        Elements {
+            decoder: decoder,
+            len: len,
+            index: 0,
+            kind: kind,
+        }
+    }
+}
+
+impl<'de, 'b, 'a> de::SeqAccess<'de> for Elements<'b, 'a> {
+    type Error = Error;
+
+    fn next_element_seed<T>(&mut self,
+                            seed: T) -> Result<Option<T::Value>, Error>
+        where T: DeserializeSeed<'de> {
+
+        if self.index == self.len {
+            return Ok(None);
+        }
+
+        match self.kind {
+            ElementKind::Plain => (),
+            ElementKind::TupleField => {
+                let name = tuple_field_name(self.index);
+
+                try!(self.decoder.validate_symbol(&name));
+            }
+            ElementKind::VariantField => {
+                let id = try!(self.decoder.read_usize());
+
+                if id != self.index {
+                    return Err(Error::BadEnumVariantId(self.index, id));
+                }
+            }
+        }
+
+        self.index += 1;
+
+        seed.deserialize(&mut *self.decoder).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.len - self.index)
+    }
+}
+
+/// Deserializer for the entries of a map
+struct Entries<'b, 'a: 'b> {
+    decoder: &'b mut Decoder<'a>,
+    /// Number of entries left to read
+    remaining: usize,
+}
+
+impl<'de, 'b, 'a> de::MapAccess<'de> for Entries<'b, 'a> {
+    type Error = Error;
+
+    fn next_key_seed<K>(&mut self,
+                        seed: K) -> Result<Option<K::Value>, Error>
+        where K: DeserializeSeed<'de> {
+
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+
+        self.remaining -= 1;
+
+        seed.deserialize(&mut *self.decoder).map(Some)
+    }
+
+    fn next_value_seed<V>(&mut self, seed: V) -> Result<V::Value, Error>
+        where V: DeserializeSeed<'de> {
+
+        seed.deserialize(&mut *self.decoder)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.remaining)
+    }
+}
+
+/// Deserializer for the fields of a struct. The fields are expected
+/// in declaration order, applying the migrations if they don't match.
+struct Fields<'b, 'a: 'b> {
+    decoder: &'b mut Decoder<'a>,
+    /// Name of the struct
+    st: &'static str,
+    fields: &'static [&'static str],
+    /// Index of the next field
+    index: usize,
+}
+
+impl<'de, 'b, 'a> de::MapAccess<'de> for Fields<'b, 'a> {
+    type Error = Error;
+
+    fn next_key_seed<K>(&mut self,
+                        seed: K) -> Result<Option<K::Value>, Error>
+        where K: DeserializeSeed<'de> {
+
+        if self.index == self.fields.len() {
+            if self.decoder.migrations.has_skips(self.st) {
+                try!(self.decoder.skip_trailing_fields(self.st));
+            }
+
+            return Ok(None);
+        }
+
+        let field = self.fields[self.index];
+
+        self.index += 1;
+
+        try!(self.decoder.read_field_symbol(self.st, field));
+
+        let key: de::value::StrDeserializer<Error> = field.into_deserializer();
+
+        seed.deserialize(key).map(Some)
+    }
+
+    fn next_value_seed<V>(&mut self, seed: V) -> Result<V::Value, Error>
+        where V: DeserializeSeed<'de> {
+
+        seed.deserialize(&mut *self.decoder)
+    }
+}
+
+/// Deserializer for an enum variant
+struct Variant<'b, 'a: 'b> {
+    decoder: &'b mut Decoder<'a>,
+    variants: &'static [&'static str],
+}
+
+impl<'de, 'b, 'a> de::EnumAccess<'de> for Variant<'b, 'a> {
+    type Error = Error;
+    type Variant = Self;
+
+    fn variant_seed<V>(self, seed: V) -> Result<(V::Value, Self), Error>
+        where V: DeserializeSeed<'de> {
+
+        let mut raw = self.decoder.take_scratch();
+
+        let name =
+            match self.decoder.read_symbol(&mut raw) {
+                Ok(symbol) =>
+                    match self.variants.iter().find(|n| symbol.matches(n)) {
+                        Some(&n) => Ok(n),
+                        None =>
+                            Err(Error::BadEnumVariant(symbol.into_string())),
+                    },
+                Err(e) => Err(e),
+            };
+
+        self.decoder.put_scratch(raw);
+
+        let name = try!(name);
+
+        let name: de::value::StrDeserializer<Error> = name.into_deserializer();
+
+        let v = try!(seed.deserialize(name));
+
+        Ok((v, self))
+    }
+}
+
+impl<'de, 'b, 'a> de::VariantAccess<'de> for Variant<'b, 'a> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T>(self, seed: T) -> Result<T::Value, Error>
+        where T: DeserializeSeed<'de> {
+
+        let id = try!(self.decoder.read_usize());
+
+        if id != 0 {
+            return Err(Error::BadEnumVariantId(0, id));
+        }
+
+        seed.deserialize(&mut *self.decoder)
+    }
+
+    fn tuple_variant<V>(self,
+                        len: usize,
+                        visitor: V) -> Result<V::Value, Error>
+        where V: Visitor<'de> {
+
+        visitor.visit_seq(Elements::new(self.decoder,
+                                        len,
+                                        ElementKind::VariantField))
+    }
+
+    fn struct_variant<V>(self,
+                         fields: &'static [&'static str],
+                         visitor: V) -> Result<V::Value, Error>
+        where V: Visitor<'de> {
+
+        self.tuple_variant(fields.len(), visitor)
+    }
+}
+
+impl<'a> ::rustc_serialize::Decoder for Decoder<'a> {
+    type Error = Error;
+
+    fn read_nil(&mut self) -> Result<(), Error> {
+        self.validate_symbol("nil")
+    }
+
+    fn read_usize(&mut self) -> Result<usize, Error> {
+        Decoder::read_usize(self)
+    }
 
-        let mut v = 0;
+    fn read_u64(&mut self) -> Result<u64, Error> {
+        Decoder::read_u64(self)
+    }
 
-        for &b in b.iter().rev() {
-            v <<= 8;
-            v |= b as u16;
-        }
+    fn read_u32(&mut self) -> Result<u32, Error> {
+        Decoder::read_u32(self)
+    }
 
-        Ok(v)
+    fn read_u16(&mut self) -> Result<u16, Error> {
+        Decoder::read_u16(self)
     }
 
     fn read_u8(&mut self) -> Result<u8, Error> {
-        let mut b = [0];
-
-        try!(self.read_bytes(&mut b));
-
-        Ok(b[0])
+        Decoder::read_u8(self)
     }
 
     fn read_isize(&mut self) -> Result<isize, Error> {
-        self.read_usize().map(|v| v as isize)
+        // isize are stored like i32s
+        Decoder::read_u32(self).map(|v| v as i32 as isize)
     }
 
     fn read_i64(&mut self) -> Result<i64, Error> {
-        self.read_u64().map(|v| v as i64)
+        Decoder::read_u64(self).map(|v| v as i64)
     }
 
     fn read_i32(&mut self) -> Result<i32, Error> {
-        self.read_u32().map(|v| v as i32)
+        Decoder::read_u32(self).map(|v| v as i32)
     }
 
     fn read_i16(&mut self) -> Result<i16, Error> {
-        self.read_u16().map(|v| v as i16)
+        Decoder::read_u16(self).map(|v| v as i16)
     }
 
     fn read_i8(&mut self) -> Result<i8, Error> {
-        self.read_u8().map(|v| v as i8)
+        Decoder::read_u8(self).map(|v| v as i8)
     }
 
     fn read_bool(&mut self) -> Result<bool, Error> {
-        match try!(self.read_u8()) {
-            0 => Ok(false),
-            1 => Ok(true),
-            n => Err(Error::BadBool(n)),
-        }
+        Decoder::read_bool(self)
     }
 
     fn read_f64(&mut self) -> Result<f64, Error> {
-        panic!()
+        Decoder::read_u64(self).map(f64::from_bits)
     }
 
     fn read_f32(&mut self) -> Result<f32, Error> {
-        panic!()
+        Decoder::read_u32(self).map(f32::from_bits)
     }
 
     fn read_char(&mut self) -> Result<char, Error> {
-        let c = try!(self.read_u32());
+        let c = try!(Decoder::read_u32(self));
 
         ::std::char::from_u32(c).ok_or(Error::BadChar(c))
     }
 
     fn read_str(&mut self) -> Result<String, Error> {
-        // First read the string length
-        let len = try!(self.read_usize());
-
-        if len > STRING_MAX_LEN {
-            return Err(Error::StringTooLong(len));
-        }
-
-        let mut buf = vec![0; len];
-
-        // Now we can read the string itself
-        try!(self.read_bytes(&mut buf));
-
-        // Finally we can convert the bytes to a String
-        String::from_utf8(buf).map_err(|e| Error::BadString(e))
+        Decoder::read_str(self)
     }
 
     fn read_enum<T, F>(&mut self, name: &str, f: F) -> Result<T, Error>
@@ -464,12 +1965,24 @@ impl<'a> ::rustc_serialize::Decoder for Decoder<'a> {
                                mut f: F) -> Result<T, Error>
         where F: FnMut(&mut Self, usize) -> Result<T, Error> {
 
-        let name = try!(self.read_str());
+        let mut raw = self.take_scratch();
 
-        match names.iter().position(|n| *n == name) {
-            Some(id) => f(self, id),
-            None => Err(Error::BadEnumVariant(name)),
-        }
+        let id =
+            match self.read_symbol(&mut raw) {
+                Ok(symbol) =>
+                    match names.iter().position(|n| symbol.matches(n)) {
+                        Some(id) => Ok(id),
+                        None =>
+                            Err(Error::BadEnumVariant(symbol.into_string())),
+                    },
+                Err(e) => Err(e),
+            };
+
+        self.put_scratch(raw);
+
+        let id = try!(id);
+
+        f(self, id)
     }
 
     fn read_enum_variant_arg<T, F>(&mut self,
@@ -477,7 +1990,7 @@ impl<'a> ::rustc_serialize::Decoder for Decoder<'a> {
                                    f: F) -> Result<T, Error>
         where F: FnOnce(&mut Self) -> Result<T, Error> {
 
-        let id = try!(self.read_usize());
+        let id = try!(Decoder::read_usize(self));
 
         if id == a_idx {
             f(self)
@@ -487,18 +2000,20 @@ impl<'a> ::rustc_serialize::Decoder for Decoder<'a> {
     }
 
     fn read_enum_struct_variant<T, F>(&mut self,
-                                      _names: &[&str],
-                                      _f: F) -> Result<T, Error>
+                                      names: &[&str],
+                                      f: F) -> Result<T, Error>
         where F: FnMut(&mut Self, usize) -> Result<T, Error> {
-        panic!()
+
+        self.read_enum_variant(names, f)
     }
 
     fn read_enum_struct_variant_field<T, F>(&mut self,
                                             _f_name: &str,
-                                            _f_idx: usize,
-                                            _f: F) -> Result<T, Error>
+                                            f_idx: usize,
+                                            f: F) -> Result<T, Error>
         where F: FnOnce(&mut Self) -> Result<T, Error> {
-        panic!()
+
+        self.read_enum_variant_arg(f_idx, f)
     }
 
     fn read_struct<T, F>(&mut self,
@@ -509,7 +2024,19 @@ impl<'a> ::rustc_serialize::Decoder for Decoder<'a> {
 
         try!(self.validate_symbol(s_name));
 
-        f(self)
+        self.structs.push(s_name.into());
+
+        let mut v = f(self);
+
+        if v.is_ok() && self.migrations.has_skips(s_name) {
+            if let Err(e) = self.skip_trailing_fields(s_name) {
+                v = Err(e);
+            }
+        }
+
+        self.structs.pop();
+
+        v
     }
 
     fn read_struct_field<T, F>(&mut self,
@@ -518,7 +2045,9 @@ impl<'a> ::rustc_serialize::Decoder for Decoder<'a> {
                                f: F) -> Result<T, Error>
         where F: FnOnce(&mut Self) -> Result<T, Error> {
 
-        try!(self.validate_symbol(f_name));
+        let st = self.structs.last().cloned().unwrap_or(String::new());
+
+        try!(self.read_field_symbol(&st, f_name));
 
         f(self)
     }
@@ -541,20 +2070,29 @@ impl<'a> ::rustc_serialize::Decoder for Decoder<'a> {
         self.read_seq_elt(a_idx, f)
     }
 
-    fn read_tuple_struct<T, F>(&mut self, _s_name: &str, _len: usize, _f: F) -> Result<T, Error>
+    fn read_tuple_struct<T, F>(&mut self,
+                               s_name: &str,
+                               len: usize,
+                               f: F) -> Result<T, Error>
         where F: FnOnce(&mut Self) -> Result<T, Error> {
-        panic!()
+
+        try!(self.validate_symbol(s_name));
+
+        self.read_tuple(len, f)
     }
 
-    fn read_tuple_struct_arg<T, F>(&mut self, _a_idx: usize, _f: F) -> Result<T, Error>
+    fn read_tuple_struct_arg<T, F>(&mut self,
+                                   a_idx: usize,
+                                   f: F) -> Result<T, Error>
         where F: FnOnce(&mut Self) -> Result<T, Error> {
-        panic!()
+
+        self.read_tuple_arg(a_idx, f)
     }
 
     fn read_option<T, F>(&mut self, mut f: F) -> Result<T, Error>
         where F: FnMut(&mut Self, bool) -> Result<T, Error> {
 
-        let is_some = try!(self.read_bool());
+        let is_some = try!(Decoder::read_bool(self));
 
         f(self, is_some)
     }
@@ -562,7 +2100,7 @@ impl<'a> ::rustc_serialize::Decoder for Decoder<'a> {
     fn read_seq<T, F>(&mut self, f: F) -> Result<T, Error>
         where F: FnOnce(&mut Self, usize) -> Result<T, Error> {
 
-        let len = try!(self.read_usize());
+        let len = try!(Decoder::read_usize(self));
 
         f(self, len)
     }
@@ -570,31 +2108,33 @@ impl<'a> ::rustc_serialize::Decoder for Decoder<'a> {
     fn read_seq_elt<T, F>(&mut self, _: usize, f: F) -> Result<T, Error>
         where F: FnOnce(&mut Self) -> Result<T, Error> {
 
-        // XXX I assume reads are done sequentially starting from 0,
-        // so I ignore idx
-
+        // Elements are read sequentially, the index isn't stored
         f(self)
     }
 
-    fn read_map<T, F>(&mut self, _f: F) -> Result<T, Error>
+    fn read_map<T, F>(&mut self, f: F) -> Result<T, Error>
         where F: FnOnce(&mut Self, usize) -> Result<T, Error> {
-        panic!()
+
+        let len = try!(Decoder::read_usize(self));
+
+        f(self, len)
     }
 
-    fn read_map_elt_key<T, F>(&mut self, _idx: usize, _f: F) -> Result<T, Error>
+    fn read_map_elt_key<T, F>(&mut self, _idx: usize, f: F) -> Result<T, Error>
         where F: FnOnce(&mut Self) -> Result<T, Error> {
-        panic!()
+
+        f(self)
     }
 
-    fn read_map_elt_val<T, F>(&mut self, _idx: usize, _f: F) -> Result<T, Error>
+    fn read_map_elt_val<T, F>(&mut self, _idx: usize, f: F) -> Result<T, Error>
         where F: FnOnce(&mut Self) -> Result<T, Error> {
-        panic!()
+
+        f(self)
     }
 
     fn error(&mut self, err: &str) -> Error {
         Error::ApplicationError(err.into())
     }
-
 }
 
 #[derive(Debug)]
@@ -628,42 +2168,90 @@ pub enum Error {
     BadTupleLength(usize, usize),
     /// Encountered an invalid bool while decoding
     BadBool(u8),
+    /// The savestate was created with a newer version of the format
+    UnsupportedVersion(u32),
+    /// Attempted to decode an unreasonably large compressed payload
+    PayloadTooLong(usize),
+    /// The compressed payload couldn't be decompressed
+    BadPayload(String),
+    /// Attempted to encode a sequence or map without a known length
+    UnknownLength,
+    /// Attempted to use a serde feature the format doesn't support
+    Unsupported(&'static str),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl ::std::error::Error for Error {
+}
+
+impl ser::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Error {
+        Error::ApplicationError(msg.to_string())
+    }
+}
+
+impl de::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Error {
+        Error::ApplicationError(msg.to_string())
+    }
 }
 
 /// "Magic" string stored in the header to indentify the file format
 pub const MAGIC: &'static [u8] = b"PKSB";
+/// Current version of the savestate format. Version 0 is the original
+/// format without a header, version 2 adds the optional flash contents
+/// after the emulator state, version 3 the compression flag, version 4
+/// the compact encoding, version 5 the metadata before the emulator
+/// state, version 6 drops the flash contents following the emulator
+/// state since it already contains them and version 7 adds the hash
+/// of the memory card directory to the header.
+pub const VERSION: u32 = 7;
 /// Maximum string length accepted by the format. This is especially
 /// useful while decoding a bogus savestate, we don't want to allocate
 /// a huge string only to discover that there's a missmatch later.
 pub const STRING_MAX_LEN: usize = 1024 * 1024;
+/// Maximum length of a compressed payload, before and after
+/// decompression. Same rationale as `STRING_MAX_LEN`.
+pub const PAYLOAD_MAX_LEN: usize = 16 * 1024 * 1024;
+/// Deflate compression level. The rewind and run-ahead snapshots are
+/// never compressed so we can afford the default level. miniz_oxide
+/// falls back on stored blocks for incompressible data at every level
+/// but level 1 flushes much smaller blocks, whose headers exceed the
+/// overhead budgeted by `max_len`.
+const COMPRESSION_LEVEL: u8 = 6;
 
 #[test]
 fn test_serialize_deserialize() {
-    use rustc_serialize::{Encodable, Decodable};
+    use serde::Deserialize;
 
-    #[derive(RustcDecodable, RustcEncodable, Debug)]
+    #[derive(Serialize, Deserialize, Debug)]
     enum Enum {
         A,
         B,
         C,
     }
 
-    #[derive(RustcDecodable, RustcEncodable, Debug)]
+    #[derive(Serialize, Deserialize, Debug)]
     enum EnumArgs {
         X(u32),
         Y(String, u8),
         Z(Vec<char>, Enum, i16),
     }
 
-    #[derive(RustcDecodable, RustcEncodable, Debug)]
+    #[derive(Serialize, Deserialize, Debug)]
     struct Struct {
         field: u32,
         field2: i32,
     }
 
-    // Automatically generate `RustcDecodable` and `RustcEncodable` trait
+    // Automatically generate `Serialize` and `Deserialize` trait
     // implementations
-    #[derive(RustcDecodable, RustcEncodable, Debug)]
+    #[derive(Serialize, Deserialize, Debug)]
     struct TestStruct  {
         data_int: u8,
         data_str: String,
@@ -692,9 +2280,10 @@ fn test_serialize_deserialize() {
     {
         let mut out = ::std::fs::File::create("/tmp/savestate").unwrap();
 
-        let mut e = Encoder::new(&mut out).unwrap();
+        let header = Header::new("test", "");
+        let mut e = Encoder::new(&mut out, &header).unwrap();
 
-        object.encode(&mut e).unwrap();
+        object.serialize(&mut e).unwrap();
     }
 
     {
@@ -702,8 +2291,640 @@ fn test_serialize_deserialize() {
 
         let mut d = Decoder::new(&mut save).unwrap();
 
-        let out: TestStruct = Decodable::decode(&mut d).unwrap();
+        let out = TestStruct::deserialize(&mut d).unwrap();
 
         println!("{:?}", out);
     }
 }
+
+#[test]
+fn test_migrations() {
+    use serde::Deserialize;
+
+    // What an older version of the struct looked like
+    #[derive(Serialize)]
+    #[serde(rename = "S")]
+    struct Old {
+        a: u32,
+        removed: u16,
+    }
+
+    #[derive(Deserialize, PartialEq, Debug)]
+    #[serde(rename = "S")]
+    struct New {
+        a: u32,
+        added: bool,
+    }
+
+    let mut state = Vec::new();
+
+    {
+        let mut header = Header::new("test", "");
+        header.card_hash = "c0ffee".into();
+
+        let mut e = Encoder::new(&mut state, &header).unwrap();
+
+        Old { a: 42, removed: 7 }.serialize(&mut e).unwrap();
+        0xdeadbeefu32.serialize(&mut e).unwrap();
+    }
+
+    {
+        let mut reader = &state[..];
+        let mut d = Decoder::new(&mut reader).unwrap();
+
+        assert_eq!(d.header().version, VERSION);
+        assert_eq!(d.header().core_version, "test");
+        assert_eq!(d.header().card_hash, "c0ffee");
+        assert!(New::deserialize(&mut d).is_err());
+    }
+
+    let mut reader = &state[..];
+    let mut d = Decoder::new(&mut reader).unwrap();
+
+    let mut migrations = Migrations::new();
+
+    migrations.default_field("S", "added", &true).unwrap();
+    migrations.skip_field::<u16>("S", "removed");
+
+    d.set_migrations(migrations);
+
+    assert_eq!(New::deserialize(&mut d).unwrap(), New { a: 42, added: true });
+    assert_eq!(u32::deserialize(&mut d).unwrap(), 0xdeadbeef);
+
+    // Savestate without header
+    let mut legacy = MAGIC.to_vec();
+
+    {
+        let mut e = Encoder {
+            writer: &mut legacy,
+            payload: None,
+            compact: false,
+        };
+
+        Old { a: 1, removed: 2 }.serialize(&mut e).unwrap();
+    }
+
+    let mut reader = &legacy[..];
+    let d = Decoder::new(&mut reader).unwrap();
+
+    assert_eq!(d.header().version, 0);
+}
+
+#[test]
+fn test_compression() {
+    use serde::Deserialize;
+
+    let data: Vec<u32> = (0..0x10000).map(|i| i / 7).collect();
+
+    let mut header = Header::new("test", "");
+    header.compressed = true;
+
+    let mut state = Vec::new();
+
+    {
+        let mut e = Encoder::new(&mut state, &header).unwrap();
+
+        data.serialize(&mut e).unwrap();
+        e.finish().unwrap();
+    }
+
+    let mut uncompressed = Vec::new();
+
+    {
+        let header = Header::new("test", "");
+        let mut e = Encoder::new(&mut uncompressed, &header).unwrap();
+
+        data.serialize(&mut e).unwrap();
+        e.finish().unwrap();
+    }
+
+    assert!(state.len() < uncompressed.len());
+
+    // Incompressible data must fit in `max_len`
+    let mut seed = 0x12345678u32;
+    let noise: Vec<u8> =
+        (0..0x30000).map(|_| {
+            // xorshift32
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed as u8
+        }).collect();
+
+    let encode = |compressed: bool| {
+        let mut header = Header::new("test", "");
+        header.compressed = compressed;
+
+        let mut out = Vec::new();
+
+        {
+            let mut e = Encoder::new(&mut out, &header).unwrap();
+
+            noise.serialize(&mut e).unwrap();
+            e.finish().unwrap();
+        }
+
+        out
+    };
+
+    let noise_uncompressed = encode(false);
+    let noise_compressed = encode(true);
+
+    assert!(noise_compressed.len() > noise_uncompressed.len());
+    assert!(noise_compressed.len() <= max_len(noise_uncompressed.len()));
+
+    // Trailing garbage must be ignored, the frontend buffer can be
+    // larger than the savestate
+    state.extend_from_slice(&[0xaa; 64]);
+
+    let mut reader = &state[..];
+    let mut d = Decoder::new(&mut reader).unwrap();
+
+    assert!(d.header().compressed);
+
+    let out = Vec::<u32>::deserialize(&mut d).unwrap();
+
+    assert_eq!(out, data);
+}
+
+#[test]
+fn test_compact() {
+    use serde::Deserialize;
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    enum E {
+        A,
+        B(u8),
+    }
+
+    let value = vec![E::B(3), E::A, E::B(0xff)];
+
+    let encode = |compact| {
+        let mut header = Header::new("test", "");
+        header.compact = compact;
+
+        let mut state = Vec::new();
+
+        {
+            let mut e = Encoder::new(&mut state, &header).unwrap();
+
+            value.serialize(&mut e).unwrap();
+            e.finish().unwrap();
+        }
+
+        state
+    };
+
+    let verbose = encode(false);
+    let compact = encode(true);
+
+    assert!(compact.len() < verbose.len());
+
+    let mut reader = &compact[..];
+    let mut d = Decoder::new(&mut reader).unwrap();
+
+    assert!(d.header().compact);
+
+    let out = Vec::<E>::deserialize(&mut d).unwrap();
+
+    assert_eq!(out, value);
+}
+
+#[test]
+fn test_all_shapes() {
+    use std::collections::BTreeMap;
+    use serde::Deserialize;
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    enum E {
+        S { x: f32, y: f64 },
+    }
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct T(u8, i64);
+
+    let mut map = BTreeMap::new();
+
+    map.insert("one".to_string(), T(1, -1));
+    map.insert("two".to_string(), T(2, ::std::i64::MIN));
+
+    let value = (E::S { x: -0.1, y: ::std::f64::consts::PI }, map);
+
+    for &compact in &[false, true] {
+        let mut header = Header::new("test", "");
+        header.compact = compact;
+
+        let mut state = Vec::new();
+
+        {
+            let mut e = Encoder::new(&mut state, &header).unwrap();
+
+            value.serialize(&mut e).unwrap();
+            e.finish().unwrap();
+        }
+
+        let mut reader = &state[..];
+        let mut d = Decoder::new(&mut reader).unwrap();
+
+        let out = <(E, BTreeMap<String, T>)>::deserialize(&mut d).unwrap();
+
+        assert_eq!(out, value);
+    }
+
+    // NaN doesn't compare equal to itself so we check the bits
+    let mut state = Vec::new();
+
+    {
+        let header = Header::new("test", "");
+        let mut e = Encoder::new(&mut state, &header).unwrap();
+
+        ::std::f64::NAN.serialize(&mut e).unwrap();
+    }
+
+    let mut reader = &state[..];
+    let mut d = Decoder::new(&mut reader).unwrap();
+
+    let nan = f64::deserialize(&mut d).unwrap();
+
+    assert_eq!(nan.to_bits(), ::std::f64::NAN.to_bits());
+}
+
+/// Types covering every shape supported by the format, used to check
+/// that the serde and `rustc-serialize` implementations are compatible
+#[cfg(test)]
+mod compat {
+    use std::collections::BTreeMap;
+
+    use rustc_serialize::{Encodable, Decodable};
+
+    #[derive(Serialize, Deserialize, RustcEncodable, RustcDecodable,
+             PartialEq, Debug)]
+    pub struct Inner {
+        pub field: u32,
+        pub field2: i32,
+    }
+
+    #[derive(Serialize, Deserialize, RustcEncodable, RustcDecodable,
+             PartialEq, Debug)]
+    pub struct Pair(pub u16, pub bool);
+
+    #[derive(Serialize, Deserialize, RustcEncodable, RustcDecodable,
+             PartialEq, Debug)]
+    pub struct Newtype(pub u8);
+
+    #[derive(Serialize, Deserialize, RustcEncodable, RustcDecodable,
+             PartialEq, Debug)]
+    pub struct Unit;
+
+    #[derive(Serialize, Deserialize, RustcEncodable, RustcDecodable,
+             PartialEq, Debug)]
+    pub enum Enum {
+        A,
+        B(u32),
+        C(String, i8),
+        D { x: u16, y: Option<char> },
+    }
+
+    #[derive(Serialize, Deserialize, RustcEncodable, RustcDecodable,
+             PartialEq, Debug)]
+    pub struct Sample {
+        pub int: u8,
+        pub signed: i64,
+        pub string: String,
+        pub vec: Vec<u16>,
+        pub inner: Inner,
+        pub tuple: (u64, char, bool),
+        pub pair: Pair,
+        pub newtype: Newtype,
+        pub unit: Unit,
+        pub nil: (),
+        pub enums: Vec<Enum>,
+        pub some: Option<i16>,
+        pub none: Option<u8>,
+        pub array: [u8; 4],
+        pub float: f32,
+        pub double: f64,
+        pub map: BTreeMap<u8, String>,
+    }
+
+    pub fn sample() -> Sample {
+        let mut map = BTreeMap::new();
+
+        map.insert(3, "three".to_string());
+        map.insert(7, "seven".to_string());
+
+        Sample {
+            int: 0x12,
+            signed: -2,
+            string: "pocket".into(),
+            vec: vec![1, 0x203],
+            inner: Inner { field: 0x42, field2: -1 },
+            tuple: (1234, '!', true),
+            pair: Pair(0xbeef, false),
+            newtype: Newtype(9),
+            unit: Unit,
+            nil: (),
+            enums: vec![Enum::A,
+                        Enum::B(5),
+                        Enum::C("x".into(), -3),
+                        Enum::D { x: 6, y: Some('@') }],
+            some: Some(-4335),
+            none: None,
+            array: [1, 2, 3, 4],
+            float: 1.5,
+            double: -0.25,
+            map: map,
+        }
+    }
+
+    /// Pointer sized integers, stored as 32bit integers
+    #[derive(Serialize, Deserialize, RustcEncodable, RustcDecodable,
+             PartialEq, Debug)]
+    pub struct Sizes {
+        #[serde(with = "super::usize32")]
+        pub u: usize,
+        #[serde(with = "super::isize32")]
+        pub i: isize,
+    }
+
+    /// `Enum::D` going through the struct variant methods of
+    /// `rustc_serialize::Encoder` and `Decoder`, which the derived
+    /// implementations don't call
+    #[derive(PartialEq, Debug)]
+    pub struct StructVariant {
+        pub x: u16,
+        pub y: Option<char>,
+    }
+
+    impl Encodable for StructVariant {
+        fn encode<S>(&self, s: &mut S) -> Result<(), S::Error>
+            where S: ::rustc_serialize::Encoder {
+            s.emit_enum("Enum", |s| {
+                s.emit_enum_struct_variant("D", 3, 2, |s| {
+                    try!(s.emit_enum_struct_variant_field("x", 0, |s| {
+                        self.x.encode(s)
+                    }));
+                    s.emit_enum_struct_variant_field("y", 1, |s| {
+                        self.y.encode(s)
+                    })
+                })
+            })
+        }
+    }
+
+    impl Decodable for StructVariant {
+        fn decode<D>(d: &mut D) -> Result<StructVariant, D::Error>
+            where D: ::rustc_serialize::Decoder {
+            d.read_enum("Enum", |d| {
+                d.read_enum_struct_variant(&["D"], |d, _| {
+                    let x = try!(d.read_enum_struct_variant_field(
+                        "x", 0, Decodable::decode));
+                    let y = try!(d.read_enum_struct_variant_field(
+                        "y", 1, Decodable::decode));
+
+                    Ok(StructVariant { x: x, y: y })
+                })
+            })
+        }
+    }
+
+    /// `Enum::D { x: 6, y: Some('@') }` encoded in the verbose format
+    /// by the derived `rustc-serialize` implementation: the fields
+    /// are stored by index, not by name
+    pub const STRUCT_VARIANT: &'static [u8] = &[
+    0x04, 0x00, 0x00, 0x00, 0x45, 0x6e, 0x75, 0x6d, 0x01, 0x00, 0x00, 0x00,
+    0x44, 0x00, 0x00, 0x00, 0x00, 0x06, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01,
+    0x40, 0x00, 0x00, 0x00,
+    ];
+
+    /// `sample()` encoded in the verbose format by the
+    /// `rustc-serialize` implementation
+    pub const VERBOSE: &'static [u8] = &[
+    0x50, 0x4b, 0x53, 0x42, 0x06, 0x00, 0x00, 0x00, 0x48, 0x65, 0x61, 0x64,
+    0x65, 0x72, 0x07, 0x00, 0x00, 0x00, 0x76, 0x65, 0x72, 0x73, 0x69, 0x6f,
+    0x6e, 0x04, 0x00, 0x00, 0x00, 0x0c, 0x00, 0x00, 0x00, 0x63, 0x6f, 0x72,
+    0x65, 0x5f, 0x76, 0x65, 0x72, 0x73, 0x69, 0x6f, 0x6e, 0x06, 0x00, 0x00,
+    0x00, 0x67, 0x6f, 0x6c, 0x64, 0x65, 0x6e, 0x09, 0x00, 0x00, 0x00, 0x62,
+    0x69, 0x6f, 0x73, 0x5f, 0x68, 0x61, 0x73, 0x68, 0x04, 0x00, 0x00, 0x00,
+    0x30, 0x31, 0x32, 0x33, 0x0a, 0x00, 0x00, 0x00, 0x63, 0x6f, 0x6d, 0x70,
+    0x72, 0x65, 0x73, 0x73, 0x65, 0x64, 0x00, 0x07, 0x00, 0x00, 0x00, 0x63,
+    0x6f, 0x6d, 0x70, 0x61, 0x63, 0x74, 0x00, 0x06, 0x00, 0x00, 0x00, 0x53,
+    0x61, 0x6d, 0x70, 0x6c, 0x65, 0x03, 0x00, 0x00, 0x00, 0x69, 0x6e, 0x74,
+    0x12, 0x06, 0x00, 0x00, 0x00, 0x73, 0x69, 0x67, 0x6e, 0x65, 0x64, 0xfe,
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x06, 0x00, 0x00, 0x00, 0x73,
+    0x74, 0x72, 0x69, 0x6e, 0x67, 0x06, 0x00, 0x00, 0x00, 0x70, 0x6f, 0x63,
+    0x6b, 0x65, 0x74, 0x03, 0x00, 0x00, 0x00, 0x76, 0x65, 0x63, 0x02, 0x00,
+    0x00, 0x00, 0x01, 0x00, 0x03, 0x02, 0x05, 0x00, 0x00, 0x00, 0x69, 0x6e,
+    0x6e, 0x65, 0x72, 0x05, 0x00, 0x00, 0x00, 0x49, 0x6e, 0x6e, 0x65, 0x72,
+    0x05, 0x00, 0x00, 0x00, 0x66, 0x69, 0x65, 0x6c, 0x64, 0x42, 0x00, 0x00,
+    0x00, 0x06, 0x00, 0x00, 0x00, 0x66, 0x69, 0x65, 0x6c, 0x64, 0x32, 0xff,
+    0xff, 0xff, 0xff, 0x05, 0x00, 0x00, 0x00, 0x74, 0x75, 0x70, 0x6c, 0x65,
+    0x03, 0x00, 0x00, 0x00, 0xd2, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x21, 0x00, 0x00, 0x00, 0x01, 0x04, 0x00, 0x00, 0x00, 0x70, 0x61, 0x69,
+    0x72, 0x04, 0x00, 0x00, 0x00, 0x50, 0x61, 0x69, 0x72, 0x07, 0x00, 0x00,
+    0x00, 0x5f, 0x66, 0x69, 0x65, 0x6c, 0x64, 0x30, 0xef, 0xbe, 0x07, 0x00,
+    0x00, 0x00, 0x5f, 0x66, 0x69, 0x65, 0x6c, 0x64, 0x31, 0x00, 0x07, 0x00,
+    0x00, 0x00, 0x6e, 0x65, 0x77, 0x74, 0x79, 0x70, 0x65, 0x07, 0x00, 0x00,
+    0x00, 0x4e, 0x65, 0x77, 0x74, 0x79, 0x70, 0x65, 0x07, 0x00, 0x00, 0x00,
+    0x5f, 0x66, 0x69, 0x65, 0x6c, 0x64, 0x30, 0x09, 0x04, 0x00, 0x00, 0x00,
+    0x75, 0x6e, 0x69, 0x74, 0x04, 0x00, 0x00, 0x00, 0x55, 0x6e, 0x69, 0x74,
+    0x03, 0x00, 0x00, 0x00, 0x6e, 0x69, 0x6c, 0x03, 0x00, 0x00, 0x00, 0x6e,
+    0x69, 0x6c, 0x05, 0x00, 0x00, 0x00, 0x65, 0x6e, 0x75, 0x6d, 0x73, 0x04,
+    0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x45, 0x6e, 0x75, 0x6d, 0x01,
+    0x00, 0x00, 0x00, 0x41, 0x04, 0x00, 0x00, 0x00, 0x45, 0x6e, 0x75, 0x6d,
+    0x01, 0x00, 0x00, 0x00, 0x42, 0x00, 0x00, 0x00, 0x00, 0x05, 0x00, 0x00,
+    0x00, 0x04, 0x00, 0x00, 0x00, 0x45, 0x6e, 0x75, 0x6d, 0x01, 0x00, 0x00,
+    0x00, 0x43, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x78, 0x01,
+    0x00, 0x00, 0x00, 0xfd, 0x04, 0x00, 0x00, 0x00, 0x45, 0x6e, 0x75, 0x6d,
+    0x01, 0x00, 0x00, 0x00, 0x44, 0x00, 0x00, 0x00, 0x00, 0x06, 0x00, 0x01,
+    0x00, 0x00, 0x00, 0x01, 0x40, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00,
+    0x73, 0x6f, 0x6d, 0x65, 0x01, 0x11, 0xef, 0x04, 0x00, 0x00, 0x00, 0x6e,
+    0x6f, 0x6e, 0x65, 0x00, 0x05, 0x00, 0x00, 0x00, 0x61, 0x72, 0x72, 0x61,
+    0x79, 0x04, 0x00, 0x00, 0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x00, 0x00,
+    0x00, 0x66, 0x6c, 0x6f, 0x61, 0x74, 0x00, 0x00, 0xc0, 0x3f, 0x06, 0x00,
+    0x00, 0x00, 0x64, 0x6f, 0x75, 0x62, 0x6c, 0x65, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0xd0, 0xbf, 0x03, 0x00, 0x00, 0x00, 0x6d, 0x61, 0x70, 0x02,
+    0x00, 0x00, 0x00, 0x03, 0x05, 0x00, 0x00, 0x00, 0x74, 0x68, 0x72, 0x65,
+    0x65, 0x07, 0x05, 0x00, 0x00, 0x00, 0x73, 0x65, 0x76, 0x65, 0x6e,
+    ];
+
+    /// `sample()` encoded in the compact format by the
+    /// `rustc-serialize` implementation
+    pub const COMPACT: &'static [u8] = &[
+    0x50, 0x4b, 0x53, 0x42, 0x06, 0x00, 0x00, 0x00, 0x48, 0x65, 0x61, 0x64,
+    0x65, 0x72, 0x07, 0x00, 0x00, 0x00, 0x76, 0x65, 0x72, 0x73, 0x69, 0x6f,
+    0x6e, 0x04, 0x00, 0x00, 0x00, 0x0c, 0x00, 0x00, 0x00, 0x63, 0x6f, 0x72,
+    0x65, 0x5f, 0x76, 0x65, 0x72, 0x73, 0x69, 0x6f, 0x6e, 0x06, 0x00, 0x00,
+    0x00, 0x67, 0x6f, 0x6c, 0x64, 0x65, 0x6e, 0x09, 0x00, 0x00, 0x00, 0x62,
+    0x69, 0x6f, 0x73, 0x5f, 0x68, 0x61, 0x73, 0x68, 0x04, 0x00, 0x00, 0x00,
+    0x30, 0x31, 0x32, 0x33, 0x0a, 0x00, 0x00, 0x00, 0x63, 0x6f, 0x6d, 0x70,
+    0x72, 0x65, 0x73, 0x73, 0x65, 0x64, 0x00, 0x07, 0x00, 0x00, 0x00, 0x63,
+    0x6f, 0x6d, 0x70, 0x61, 0x63, 0x74, 0x01, 0xc7, 0x8f, 0xf8, 0xdc, 0x5e,
+    0x7e, 0xe9, 0x95, 0x12, 0x15, 0x20, 0x71, 0xb5, 0xfe, 0xff, 0xff, 0xff,
+    0xff, 0xff, 0xff, 0xff, 0x38, 0x65, 0xc1, 0x17, 0x06, 0x00, 0x00, 0x00,
+    0x70, 0x6f, 0x63, 0x6b, 0x65, 0x74, 0xe5, 0x50, 0x30, 0x73, 0x02, 0x00,
+    0x00, 0x00, 0x01, 0x00, 0x03, 0x02, 0x27, 0x6a, 0xde, 0xd8, 0xc7, 0x6e,
+    0x9b, 0x7e, 0x67, 0x62, 0x82, 0x67, 0x42, 0x00, 0x00, 0x00, 0xcf, 0xcb,
+    0x40, 0x47, 0xff, 0xff, 0xff, 0xff, 0x31, 0x23, 0x72, 0x92, 0x03, 0x00,
+    0x00, 0x00, 0xd2, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x21, 0x00,
+    0x00, 0x00, 0x01, 0xb1, 0x5a, 0x44, 0x7c, 0xd1, 0xf1, 0x23, 0xe3, 0xfe,
+    0x30, 0x4e, 0xe6, 0xef, 0xbe, 0x91, 0x32, 0x4e, 0xe7, 0x00, 0xf9, 0xb8,
+    0x71, 0xa8, 0x99, 0x48, 0x48, 0xc2, 0xfe, 0x30, 0x4e, 0xe6, 0x09, 0x07,
+    0x1a, 0xb5, 0xe8, 0x27, 0x66, 0xe1, 0x68, 0xec, 0xf8, 0xa3, 0x0d, 0xec,
+    0xf8, 0xa3, 0x0d, 0x09, 0xc5, 0x19, 0x31, 0x04, 0x00, 0x00, 0x00, 0x20,
+    0xda, 0x4d, 0xe8, 0xcc, 0xf6, 0x0b, 0xc4, 0x20, 0xda, 0x4d, 0xe8, 0x85,
+    0xfb, 0x0b, 0xc7, 0x00, 0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x20,
+    0xda, 0x4d, 0xe8, 0xf2, 0xf9, 0x0b, 0xc6, 0x00, 0x00, 0x00, 0x00, 0x01,
+    0x00, 0x00, 0x00, 0x78, 0x01, 0x00, 0x00, 0x00, 0xfd, 0x20, 0xda, 0x4d,
+    0xe8, 0x13, 0xf2, 0x0b, 0xc1, 0x00, 0x00, 0x00, 0x00, 0x06, 0x00, 0x01,
+    0x00, 0x00, 0x00, 0x01, 0x40, 0x00, 0x00, 0x00, 0x71, 0x1c, 0x61, 0xf3,
+    0x01, 0x11, 0xef, 0xdb, 0xaf, 0xa7, 0xad, 0x00, 0x26, 0xad, 0x58, 0x8a,
+    0x04, 0x00, 0x00, 0x00, 0x01, 0x02, 0x03, 0x04, 0x85, 0x5d, 0xc4, 0xa6,
+    0x00, 0x00, 0xc0, 0x3f, 0x08, 0x0f, 0xeb, 0xa0, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0xd0, 0xbf, 0xb1, 0xef, 0xa2, 0xdf, 0x02, 0x00, 0x00, 0x00,
+    0x03, 0x05, 0x00, 0x00, 0x00, 0x74, 0x68, 0x72, 0x65, 0x65, 0x07, 0x05,
+    0x00, 0x00, 0x00, 0x73, 0x65, 0x76, 0x65, 0x6e,
+    ];
+}
+
+#[test]
+fn test_rustc_serialize_compat() {
+    use serde::Deserialize;
+
+    for &(compact, expected) in &[(false, compat::VERBOSE),
+                                  (true, compat::COMPACT)] {
+        let mut header = Header::new("golden", "0123");
+        header.compact = compact;
+        // The golden savestates were created with version 4 of the
+        // format
+        header.version = 4;
+
+        let mut state = Vec::new();
+
+        {
+            let mut e = Encoder::new(&mut state, &header).unwrap();
+
+            compat::sample().serialize(&mut e).unwrap();
+            e.finish().unwrap();
+        }
+
+        assert_eq!(&state[..], expected);
+
+        let mut reader = expected;
+        let mut d = Decoder::new(&mut reader).unwrap();
+
+        let out = compat::Sample::deserialize(&mut d).unwrap();
+
+        assert_eq!(out, compat::sample());
+        assert!(reader.is_empty());
+
+        // Same thing through `rustc_serialize::Encoder`, used by the
+        // emulator state
+        let mut state = Vec::new();
+
+        {
+            let mut e = Encoder::new(&mut state, &header).unwrap();
+
+            compat::sample().encode(&mut e).unwrap();
+            e.finish().unwrap();
+        }
+
+        assert_eq!(&state[..], expected);
+
+        let mut reader = expected;
+        let mut d = Decoder::new(&mut reader).unwrap();
+
+        let out = compat::Sample::decode(&mut d).unwrap();
+
+        assert_eq!(out, compat::sample());
+        assert!(reader.is_empty());
+    }
+
+    // Struct variants use the same encoding whether they go through
+    // serde, the derived `rustc-serialize` implementations or the
+    // struct variant methods
+    let variant = compat::Enum::D { x: 6, y: Some('@') };
+    let sv = compat::StructVariant { x: 6, y: Some('@') };
+
+    for &serde in &[true, false] {
+        let mut state = Vec::new();
+
+        {
+            let mut e = Encoder {
+                writer: &mut state,
+                payload: None,
+                compact: false,
+            };
+
+            if serde {
+                variant.serialize(&mut e).unwrap();
+            } else {
+                sv.encode(&mut e).unwrap();
+            }
+        }
+
+        assert_eq!(&state[..], compat::STRUCT_VARIANT);
+    }
+
+    let mut legacy = MAGIC.to_vec();
+
+    legacy.extend_from_slice(compat::STRUCT_VARIANT);
+
+    let mut reader = &legacy[..];
+    let mut d = Decoder::new(&mut reader).unwrap();
+
+    assert_eq!(compat::StructVariant::decode(&mut d).unwrap(), sv);
+
+    // `rustc-serialize` stores usize and isize as 32bit integers,
+    // serde needs `usize32` and `isize32` to do the same
+    let sizes = compat::Sizes { u: 0x1234, i: -2 };
+
+    for &serde in &[true, false] {
+        let mut state = Vec::new();
+
+        {
+            let header = Header::new("test", "");
+            let mut e = Encoder::new(&mut state, &header).unwrap();
+
+            if serde {
+                sizes.serialize(&mut e).unwrap();
+            } else {
+                sizes.encode(&mut e).unwrap();
+            }
+        }
+
+        assert_eq!(&state[state.len() - 18..],
+                   &[0x01, 0x00, 0x00, 0x00, b'u',
+                     0x34, 0x12, 0x00, 0x00,
+                     0x01, 0x00, 0x00, 0x00, b'i',
+                     0xfe, 0xff, 0xff, 0xff][..]);
+
+        for &serde in &[true, false] {
+            let mut reader = &state[..];
+            let mut d = Decoder::new(&mut reader).unwrap();
+
+            let out =
+                if serde {
+                    compat::Sizes::deserialize(&mut d).unwrap()
+                } else {
+                    compat::Sizes::decode(&mut d).unwrap()
+                };
+
+            assert_eq!(out, sizes);
+            assert!(reader.is_empty());
+        }
+    }
+
+    if ::std::usize::MAX as u64 > 0xffff_ffff {
+        let mut state = Vec::new();
+        let mut e = Encoder {
+            writer: &mut state,
+            payload: None,
+            compact: false,
+        };
+
+        let big = compat::Sizes { u: ::std::usize::MAX, i: 0 };
+
+        assert!(big.serialize(&mut e).is_err());
+    }
+}